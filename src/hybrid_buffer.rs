@@ -0,0 +1,252 @@
+//! `HybridBuffer` is a `SmallBuffer` that keeps the items beyond its inline capacity in a single
+//! growable vector instead of a chain of blocks.
+//! The items in the vector are contiguous in memory, so iterating over a buffer that grew large
+//! doesn't chase pointers, at the cost of a lock for the pushes that don't fit inline.
+
+use std::iter::FusedIterator;
+use std::ops::Deref;
+use std::slice;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use small_buffer::{self, SmallBuffer};
+
+/// A small inline-allocated buffer that spills into a vector. Pushing values can be done
+/// asynchronously. Removing values needs exclusive access.
+///
+/// The first `N` items are stored inline and pushed without a lock, like in `SmallBuffer`. The
+/// items beyond them are pushed to a `Vec` behind a lock, in the order the pushes take it.
+///
+/// Unlike `SmallBuffer`, `get` and `iter` need exclusive access. A push can grow the `Vec` and move
+/// its items, so a reference into the tail can't outlive the lock, and `&self` can't hand one out.
+/// With shared access, the tail is read as a slice through `tail`, which holds the lock while the
+/// slice is in use.
+///
+/// `N` defaults to 16.
+pub struct HybridBuffer<T, const N: usize = 16> {
+    // only holds the items at inline indices, but counts all of them
+    inline: SmallBuffer<T, N>,
+    tail: Mutex<Vec<T>>,
+}
+
+impl<T, const N: usize> HybridBuffer<T, N> {
+    /// Creates an empty buffer.
+    pub fn new() -> Self {
+        HybridBuffer {
+            inline: SmallBuffer::new(),
+            tail: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Pushes the item asynchronously. Takes the lock of the tail if the inline slots are taken.
+    pub fn push(&self, item: T) {
+        let index = self.inline.reserve_index();
+        if index < N {
+            self.inline.write_inline(item, index);
+        } else {
+            self.tail.lock().unwrap_or_else(PoisonError::into_inner).push(item);
+        }
+    }
+
+    /// The number of items in the buffer.
+    ///
+    /// `push` reserves a place for the item before writing it, so during concurrent pushes this is an
+    /// upper bound: some of the counted items might not be written yet.
+    pub fn len(&self) -> usize {
+        self.inline.len()
+    }
+
+    /// Returns `true` if the buffer has no items. During concurrent pushes, see `len`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes the last pushed item and returns it, or `None` if the buffer is empty.
+    pub fn pop(&mut self) -> Option<T> {
+        let len = self.len();
+        if len <= N {
+            return self.inline.pop();
+        }
+        let item = self.tail_mut().pop();
+        self.inline.set_len(len - 1);
+        item
+    }
+
+    /// Returns the item at `index`, or `None` if `index` is not lower than `len()`. Needs exclusive
+    /// access to read the tail without its lock, see `tail` for shared access.
+    pub fn get(&mut self, index: usize) -> Option<&T> {
+        self.get_mut(index).map(|item| &*item)
+    }
+
+    /// Returns a mutable reference to the item at `index`, or `None` if `index` is not lower than
+    /// `len()`.
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        if index < N {
+            self.inline.get_mut(index)
+        } else {
+            self.tail_mut().get_mut(index - N)
+        }
+    }
+
+    /// Locks the items beyond the inline capacity, giving access to them as a slice. Pushes that
+    /// don't fit inline wait until the returned guard is dropped.
+    pub fn tail(&self) -> Tail<'_, T> {
+        Tail {
+            guard: self.tail.lock().unwrap_or_else(PoisonError::into_inner),
+        }
+    }
+
+    /// Creates an iterator over the items, the inline ones first. Needs exclusive access to read the
+    /// tail without its lock.
+    pub fn iter(&mut self) -> Iter<'_, T, N> {
+        let inline_len = self.len().min(N);
+        Iter {
+            inline: self.inline.iter_inline(inline_len),
+            tail: self.tail.get_mut().unwrap_or_else(PoisonError::into_inner).iter(),
+        }
+    }
+
+    /// Drops all the items. The memory of the tail is kept for reuse.
+    pub fn clear(&mut self) {
+        let inline_len = self.len().min(N);
+        self.tail_mut().clear();
+        self.inline.set_len(inline_len);
+        self.inline.clear();
+    }
+
+    fn tail_mut(&mut self) -> &mut Vec<T> {
+        self.tail.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<T, const N: usize> Drop for HybridBuffer<T, N> {
+    fn drop(&mut self) {
+        // the inline buffer only drops its own items, the tail drops the rest
+        let inline_len = self.len().min(N);
+        self.inline.set_len(inline_len);
+    }
+}
+
+impl<T, const N: usize> Default for HybridBuffer<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The items of a `HybridBuffer` beyond its inline capacity, locked. Created by
+/// `HybridBuffer::tail`.
+pub struct Tail<'a, T: 'a> {
+    guard: MutexGuard<'a, Vec<T>>,
+}
+
+impl<'a, T> Deref for Tail<'a, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        &self.guard
+    }
+}
+
+/// An iterator over the items of a `HybridBuffer`. Created by `HybridBuffer::iter`.
+pub struct Iter<'a, T: 'a, const N: usize = 16> {
+    inline: small_buffer::Iter<'a, T, N>,
+    tail: slice::Iter<'a, T>,
+}
+
+impl<'a, T, const N: usize> Iterator for Iter<'a, T, N> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inline.next().or_else(|| self.tail.next())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.inline.size_hint().0 + self.tail.len();
+        (len, Some(len))
+    }
+}
+
+impl<'a, T, const N: usize> ExactSizeIterator for Iter<'a, T, N> {}
+
+impl<'a, T, const N: usize> FusedIterator for Iter<'a, T, N> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    #[test]
+    fn push_and_iter() {
+        let mut buf = HybridBuffer::<usize, 4>::new();
+        assert!(buf.is_empty());
+        for i in 0..10 {
+            buf.push(i);
+        }
+        assert_eq!(buf.len(), 10);
+        assert_eq!(&*buf.tail(), &[4, 5, 6, 7, 8, 9]);
+        let iter = buf.iter();
+        assert_eq!(iter.len(), 10);
+        assert!(iter.cloned().eq(0..10));
+        *buf.get_mut(2).unwrap() = 20;
+        *buf.get_mut(7).unwrap() = 70;
+        assert!(buf.get_mut(10).is_none());
+        assert_eq!(buf.get(2), Some(&20));
+        assert_eq!(buf.get(9), Some(&9));
+        assert_eq!(buf.get(10), None);
+        assert_eq!(buf.iter().cloned().collect::<Vec<_>>(), [0, 1, 20, 3, 4, 5, 6, 70, 8, 9]);
+    }
+
+    #[test]
+    fn pop() {
+        let mut buf = HybridBuffer::<usize, 4>::new();
+        for i in 0..6 {
+            buf.push(i);
+        }
+        for i in (0..6).rev() {
+            assert_eq!(buf.pop(), Some(i));
+            assert_eq!(buf.len(), i);
+        }
+        assert_eq!(buf.pop(), None);
+        buf.push(10);
+        assert_eq!(buf.iter().cloned().collect::<Vec<_>>(), [10]);
+    }
+
+    #[test]
+    fn drops_items() {
+        let item = Arc::new(0);
+        let mut buf = HybridBuffer::<_, 4>::new();
+        for _ in 0..10 {
+            buf.push(item.clone());
+        }
+        buf.clear();
+        assert_eq!(Arc::strong_count(&item), 1);
+        assert!(buf.is_empty());
+        for _ in 0..10 {
+            buf.push(item.clone());
+        }
+        drop(buf);
+        assert_eq!(Arc::strong_count(&item), 1);
+    }
+
+    #[test]
+    fn threads() {
+        use crossbeam::scope;
+
+        let mut buf = HybridBuffer::<usize>::new();
+        scope(|s| {
+            for t in 0..4 {
+                let buf = &buf;
+                s.spawn(move || {
+                    for i in 0..100 {
+                        buf.push(t * 100 + i);
+                    }
+                });
+            }
+        });
+        assert_eq!(buf.len(), 400);
+        assert_eq!(buf.tail().len(), 384);
+        let mut items: Vec<_> = buf.iter().cloned().collect();
+        items.sort();
+        assert!(items.into_iter().eq(0..400));
+    }
+}
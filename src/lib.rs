@@ -1,7 +1,6 @@
-#![feature(allocator_api)]
-#![feature(arc_counts)]
 #![deny(missing_docs)]
 #![allow(unknown_lints)]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 
 //! `VeryFast` is a collection of useful tools needed mostly by game developers.
 //! It is designed to work well in multi threaded contexts.
@@ -10,16 +9,41 @@
 //! like a `Box`, but allocates in batches and reuses the memory instead of deallocating
 //! when Dropped!
 //!
-//! ## Nightly Requirements:
-//! Nightly is required because of the next features:
+//! # Features
 //!
-//! - `#![feature(allocator_api)]`: Custom allocation strategy for `Pool`
+//! - `std` (enabled by default): Without it the crate is `no_std` and only needs `alloc`. Only
+//!   `pool` is available then. It uses spin locks, and threads don't keep freed slots for
+//!   themselves.
+//! - `crossbeam` (enabled by default): With `std`, the shared free list of a `Pool` is the
+//!   lock-free queue of crossbeam, or its stack with `FreeOrder::Lifo`. Without it, the queue is
+//!   behind a lock and the stack is a built-in one with lock-free pushes, and the crate doesn't
+//!   depend on crossbeam.
+//! - `serde`: Serialization of `SmallBuffer`.
+//! - `rayon`: Parallel draining of `SmallBuffer`, with `SmallBuffer::par_drain`.
+//! - `diagnostics`: Methods for inspecting the memory layout of a `Pool`, like
+//!   `Pool::free_distribution`. They are slow and meant for tuning, not for release builds.
+//! - `numa`: `Pool::with_numa_node`, which places the batches of a `Pool` on a NUMA node. Only has
+//!   an effect on Linux.
 
 
+extern crate alloc;
+#[cfg(any(feature = "std", test))]
+extern crate core;
+#[cfg(any(all(feature = "std", feature = "crossbeam"), test))]
 extern crate crossbeam;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(all(feature = "numa", target_os = "linux"))]
+extern crate libc;
 
 pub mod pool;
+#[cfg(feature = "std")]
+pub mod hybrid_buffer;
+#[cfg(feature = "std")]
 pub mod small_buffer;
+mod sync;
 
 // mod tiny_buffer;
 
@@ -8,7 +8,8 @@
 //!
 //! When objects are dropped, their memory is returned to the pool to be reused for
 //! future allocations.
-//! Only when all the objects and the `Pool` are dropped will the memory be released.
+//! Only when all the objects and the `Pool` are dropped will the memory be released, unless
+//! `Pool::shrink_to_fit` is used to release batches that are completely free.
 //!
 //! It gives the option to allocate each object on a separate CPU cache line, increasing performance
 //! of multithreaded access to adjacent elements.
@@ -17,6 +18,12 @@
 //! owned object will be dropped as well. The memory, however, will be returned to the `Pool` it
 //! was allocated from to be available for other allocations.
 //!
+//! A `RecyclePool` keeps the values of dropped objects and resets them with `Recycle` for reuse,
+//! instead of constructing new ones.
+//!
+//! A `SharedPool` can be cloned to share a `Pool` without a borrow. Its `OwnedObject`s keep the
+//! `Pool` alive, so they are not bound to the lifetime of any handle.
+//!
 //! # Examples
 //!
 //! ```
@@ -70,38 +77,647 @@
 //! }
 //! ```
 
-use std::heap::{Heap, Layout, Alloc};
-use std::fmt;
-use std::mem;
-use std::ops::{Deref, DerefMut};
-use std::ptr;
-use std::sync::Mutex;
+use alloc::alloc::{self, GlobalAlloc, Layout};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::borrow::{Borrow, BorrowMut};
+use core::cmp::Ordering as CmpOrdering;
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "std")]
+use alloc::sync::Weak;
+#[cfg(feature = "std")]
+use core::cell::RefCell;
 
-use super::crossbeam::sync::MsQueue;
+use sync::{FreeList, FreeSlots, Mutex, Queue, RwLock};
 
 /// A fast heap-allocator. Allocates objects in a batch, but transfers the ownership to the `Object`.
 ///
 /// Allocations will first check if there is an already free slot to use, and use that.
 /// If no, It will take a lock and allocate a batch of memory.
 ///
+/// Each thread keeps a few of the slots it freed for its own next allocations, so a thread that
+/// drops and pushes objects in turn doesn't touch the shared free list.
+///
 /// When objects are dropped, their memory will be returned to the pool to be used again later.
 /// The memory of the batches will be deallocated only when the `Pool` and all the related `Object`s
 /// are dropped.
-pub struct Pool<T> {
-    data: Mutex<Vec<*const T>>,
-    free: MsQueue<*mut T>,
+///
+/// The batches are allocated with `A`, which is the global heap by default.
+// `repr(C)` and no field depending on `T`, so a `Pool<T>` can be used as a `Pool<U>` by `Object::cast`
+#[repr(C)]
+pub struct Pool<T, A: GlobalAlloc = Heap> {
+    allocator: A,
+    id: usize,
+    // the start of every batch
+    data: Mutex<Vec<*const u8>>,
+    free: FreeSlots<usize>,
+    spilled: Arc<Mutex<Vec<usize>>>,
     layout: Layout,
     batch: usize,
     stride: usize,
+    capacity: AtomicUsize,
+    max_capacity: usize,
+    live: AtomicUsize,
+    // `None` unless the `Pool` was created with `track_liveness`
+    liveness: Option<RwLock<Vec<LiveBlock>>>,
+    // `None` unless the `Pool` was created with `track_generations` or in debug builds
+    generations: Option<RwLock<Vec<GenerationBlock>>>,
+    // the next generation to give a slot, so a generation is never given twice
+    next_generation: AtomicUsize,
+    return_hook: Option<ReturnHook>,
+    growth: GrowthPolicy,
+    // the number of batches the next expansion allocates
+    growth_step: AtomicUsize,
+    empty_batch_policy: EmptyBatchPolicy,
+    // `None` when the policy is `Keep`
+    batch_use: Option<RwLock<Vec<BatchUse>>>,
+    // `None` unless the `Pool` was created with `overflow`
+    overflow: Option<Overflow>,
+    oom_handler: Option<OomHandler>,
+    #[cfg(feature = "numa")]
+    numa_node: Option<u32>,
+    _marker: PhantomData<*const T>,
 }
 
 /// A pointer type that owns its content.
 ///
 /// Created from a `Pool`. The `Object` owns the value inside it and has exclusive access to it.
 ///
-pub struct Object<'active, T: 'active> {
+/// In debug builds, accessing an `Object` whose slot was released panics. This can only happen
+/// when unsafe code duplicates an `Object`, and the value it points to isn't its own anymore.
+pub struct Object<'active, T: 'active, A: 'active + GlobalAlloc = Heap> {
+    obj: *mut T,
+    manager: &'active Pool<T, A>,
+    // the generation of the slot when the `Object` was created, checked on access in debug builds
+    #[cfg(debug_assertions)]
+    generation: usize,
+}
+
+/// An `Object` that gives access only to a part of its content.
+///
+/// Created by `Object::map`. It still owns the whole value: when dropped, the whole value is
+/// dropped and the memory is returned to the `Pool`.
+pub struct MappedObject<'active, T: 'active, U, A: 'active + GlobalAlloc = Heap> {
+    // only held to be dropped together with the `MappedObject`
+    #[allow(dead_code)]
+    object: Object<'active, T, A>,
+    value: *mut U,
+}
+
+/// A handle to a value in a `Pool` that doesn't keep it alive, like `rc::Weak`.
+///
+/// Created by `Object::downgrade`. It only remembers the slot of the value and the generation the
+/// slot had, so the value can still be dropped and its slot reused. The `Pool` gives a slot a new
+/// generation every time it is released, which tells whether the value is still the one the
+/// handle was made for.
+///
+/// Zero-sized values all share the same address, so their handles can't tell them apart and
+/// always look alive. `Pool::rebuild_free_list` makes all the existing handles look dead.
+pub struct WeakObject<T, A: GlobalAlloc = Heap> {
+    obj: usize,
+    generation: usize,
+    pool: usize,
+    _marker: PhantomData<fn(&Pool<T, A>)>,
+}
+
+/// Compares and hashes an `Object`, or a reference to one, by identity instead of by its value.
+///
+/// An owned `ByAddress<Object<T>>` compares the slots, like `Object::ptr_eq`. Two `Object`s never
+/// own the same slot at the same time, so it is a stable key even when `T` is not `Hash` or `Eq`,
+/// or when values compare equal. `ByAddress<&Object<T>>` compares the addresses of the `Object`s
+/// themselves, which tells apart references to different `Object`s.
+///
+/// Zero-sized values all share the same address, so owned handles to them all compare equal: a set
+/// of `ByAddress<Object<()>>` holds at most one of them. References to their handles are still
+/// told apart.
+#[derive(Debug)]
+pub struct ByAddress<O>(pub O);
+
+/// An `Object` shared between threads, like an `Arc<T>` whose value lives in a `Pool`.
+///
+/// Created by `Object::into_shared`. Cloning it only increments a reference count, and each clone
+/// gives out only shared references to the value, so it can be read from many threads at once
+/// but never mutated. The value is dropped and its slot returned to the `Pool` when the last
+/// clone is dropped.
+///
+/// Like `Arc<T>`, it is `Send` and `Sync` when `T` is both `Send` and `Sync`: any clone may be the
+/// one that drops the value, and all of them read it concurrently.
+pub struct SharedObject<'active, T: 'active, A: 'active + GlobalAlloc = Heap> {
+    object: Arc<Object<'active, T, A>>,
+}
+
+/// A `Pool` behind an `Arc`. Cloning it gives another handle to the same `Pool`.
+///
+/// Allocations return `OwnedObject`s, which keep the `Pool` alive by holding a handle instead of
+/// borrowing it, so they can outlive the scope that created the `Pool`.
+///
+/// The `Pool` is dropped together with the last handle, whether that is a `SharedPool` or an
+/// `OwnedObject`. An `OwnedObject` drops its value and returns its slot before releasing its
+/// handle, so the memory of the batches is never freed while a value is still in it.
+///
+/// All the methods of `Pool` are available through `Deref`.
+pub struct SharedPool<T, A: GlobalAlloc = Heap> {
+    pool: Arc<Pool<T, A>>,
+}
+
+/// A pointer type that owns its content, like `Object`, allocated from a `SharedPool`.
+///
+/// Holds a handle to its `Pool` instead of borrowing it.
+pub struct OwnedObject<T, A: GlobalAlloc = Heap> {
+    obj: *mut T,
+    manager: Arc<Pool<T, A>>,
+}
+
+/// A `Pool` for unsized values, like trait objects, stored as `Box<T>`.
+///
+/// The `Pool` allocates fixed-size slots, so only the `Box` pointers are pooled: each value still
+/// lives in its own heap allocation, made when it is boxed and freed when its `Object` is dropped.
+/// The slots of the pointers are reused like in any `Pool`.
+///
+/// All the methods of `Pool<Box<T>>` are available through `Deref`.
+pub struct DynPool<T: ?Sized, A: GlobalAlloc = Heap> {
+    pool: Pool<Box<T>, A>,
+}
+
+/// A value that can be reset for reuse by a `RecyclePool`, keeping its resources, like the memory
+/// of a `Vec`.
+pub trait Recycle {
+    /// Resets the value to a clean state.
+    fn recycle(&mut self);
+}
+
+impl<T> Recycle for Vec<T> {
+    #[inline]
+    fn recycle(&mut self) {
+        self.clear();
+    }
+}
+
+impl Recycle for String {
+    #[inline]
+    fn recycle(&mut self) {
+        self.clear();
+    }
+}
+
+/// A `Pool` that reuses the values of dropped objects instead of only their memory.
+///
+/// When a `Recycled` object is dropped, its value is not dropped: it stays in its slot, and is reset
+/// with `Recycle::recycle` by the `acquire` that reuses the slot. New slots are initialized with
+/// `T::default()`. The values left in free slots are dropped together with the `RecyclePool`.
+///
+/// Values whose `recycle` panics are dropped and their slots are returned to the `Pool`.
+pub struct RecyclePool<T, A: GlobalAlloc = Heap> {
+    pool: Pool<T, A>,
+    recycled: Queue<usize>,
+    kept: AtomicUsize,
+}
+
+/// A pointer type that owns its content, like `Object`, allocated from a `RecyclePool`.
+///
+/// When dropped, the value is kept by the `RecyclePool` for reuse.
+pub struct Recycled<'active, T: 'active, A: 'active + GlobalAlloc = Heap> {
     obj: *mut T,
-    manager: &'active Pool<T>,
+    manager: &'active RecyclePool<T, A>,
+}
+
+/// The global heap allocator, as registered with `#[global_allocator]`. The default allocator of a
+/// `Pool`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Heap;
+
+unsafe impl GlobalAlloc for Heap {
+    #[inline]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        alloc::alloc(layout)
+    }
+
+    #[inline]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        alloc::dealloc(ptr, layout)
+    }
+}
+
+/// Configures and creates a `Pool`, as an alternative to the constructors with many positional
+/// parameters.
+///
+/// Starts with the configuration of `Pool::new`. Every method sets one parameter.
+///
+/// # Examples
+///
+/// ```
+/// use veryfast::pool::{Pool, PoolBuilder};
+///
+/// let pool = PoolBuilder::<u64>::new()
+///     .align_to_cache(true)
+///     .prewarm(100)
+///     .build();
+/// assert!(pool.capacity() >= 100);
+///
+/// // the same layout as the positional constructor
+/// let built = PoolBuilder::<u64>::new().align_to_cache(true).build();
+/// assert_eq!(built.stats(), Pool::<u64>::with_params(true).stats());
+/// ```
+pub struct PoolBuilder<T, A: GlobalAlloc = Heap> {
+    align_to_cache: bool,
+    cache_line_size: usize,
+    number_of_sets: usize,
+    track_liveness: bool,
+    track_generations: bool,
+    max_capacity: usize,
+    prewarm: usize,
+    return_hook: Option<ReturnHook>,
+    growth: GrowthPolicy,
+    free_order: FreeOrder,
+    min_align: usize,
+    empty_batch_policy: EmptyBatchPolicy,
+    overflow: bool,
+    oom_handler: Option<OomHandler>,
+    #[cfg(feature = "numa")]
+    numa_node: Option<u32>,
+    allocator: A,
+    _marker: PhantomData<T>,
+}
+
+/// How many batches a `Pool` allocates at a time when it runs out of free slots, set by
+/// `PoolBuilder::growth`.
+///
+/// Allocating more batches at a time takes the lock less often while the `Pool` grows fast, but
+/// might allocate memory that is never used.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GrowthPolicy {
+    /// Allocates this many batches every time. `Fixed(1)` is the default.
+    Fixed(usize),
+    /// Allocates one batch the first time, and multiplies the number of batches by this factor
+    /// every time after that, up to 64 batches at a time.
+    Geometric(usize),
+}
+
+/// The order in which a `Pool` reuses free slots, set by `PoolBuilder::free_order`.
+///
+/// With the `std` feature, every thread first reuses the last few slots it freed itself. The order
+/// applies to the slots shared between threads.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FreeOrder {
+    /// The slot freed first is reused first. Spreads the usage over all the slots.
+    Fifo,
+    /// The slot freed last is reused first. Its memory is more likely to still be in the CPU
+    /// cache.
+    Lifo,
+}
+
+impl Default for FreeOrder {
+    /// `Fifo` with the `std` feature. Without it there are no per-thread slots, so the default is
+    /// `Lifo` to keep reusing recently freed memory.
+    fn default() -> Self {
+        if cfg!(feature = "std") {
+            FreeOrder::Fifo
+        } else {
+            FreeOrder::Lifo
+        }
+    }
+}
+
+/// What a `Pool` does with a batch when all of its slots become free, set by
+/// `Pool::set_empty_batch_policy`.
+///
+/// `Release` and `Callback` are an eager alternative to calling `shrink_to_fit`. They make the
+/// `Pool` count the used slots of every batch: taking or returning a slot also finds its batch, a
+/// binary search, and updates an atomic counter. When the last used slot of a batch is returned,
+/// releasing it takes the lock and empties the free list like `shrink_to_fit`, so it is
+/// `O(capacity)`.
+///
+/// A batch is only released if all of its slots are in the shared free list. If other threads
+/// keep some of them for their own allocations, it is kept.
+#[derive(Default)]
+pub enum EmptyBatchPolicy {
+    /// Batches are only released by `shrink_to_fit`. The default.
+    #[default]
+    Keep,
+    /// Empty batches are released right away. A `Pool` whose usage goes up and down around a
+    /// batch boundary will allocate and release the same batch over and over.
+    Release,
+    /// The function is called with the statistics of the `Pool` when a batch becomes empty, and
+    /// the batch is released if it returns `true`. It must not use the `Pool`.
+    Callback(Box<dyn Fn(&PoolStats) -> bool + Send + Sync>),
+}
+
+impl fmt::Debug for EmptyBatchPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            EmptyBatchPolicy::Keep => f.write_str("Keep"),
+            EmptyBatchPolicy::Release => f.write_str("Release"),
+            EmptyBatchPolicy::Callback(_) => f.write_str("Callback(..)"),
+        }
+    }
+}
+
+/// The most batches `GrowthPolicy::Geometric` allocates at a time.
+const MAX_GROWTH_BATCHES: usize = 64;
+
+/// What a `Pool` does when the allocation of a batch fails, as decided by its OOM handler. See
+/// `Pool::set_oom_handler`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OomAction {
+    /// Tries to allocate the batch again, after the handler freed some memory.
+    Retry,
+    /// Gives up like without a handler: `push` panics and `try_push` returns the value.
+    Abort,
+}
+
+/// A function called when a `Pool` fails to allocate a batch. See `Pool::set_oom_handler`.
+pub type OomHandler = Box<dyn Fn() -> OomAction + Send + Sync>;
+
+/// A function called with the slot of every value returned to a `Pool`. See
+/// `Pool::with_return_hook`.
+pub type ReturnHook = Box<dyn Fn(*mut ()) + Send + Sync>;
+
+impl<T> PoolBuilder<T> {
+    /// Creates a builder with the configuration of `Pool::new`.
+    #[inline]
+    pub fn new() -> PoolBuilder<T> {
+        PoolBuilder {
+            align_to_cache: false,
+            cache_line_size: cache_line_size(),
+            number_of_sets: 64,
+            track_liveness: false,
+            track_generations: false,
+            max_capacity: usize::MAX,
+            prewarm: 0,
+            return_hook: None,
+            growth: GrowthPolicy::Fixed(1),
+            free_order: FreeOrder::default(),
+            min_align: mem::align_of::<T>(),
+            empty_batch_policy: EmptyBatchPolicy::Keep,
+            overflow: false,
+            oom_handler: None,
+            #[cfg(feature = "numa")]
+            numa_node: None,
+            allocator: Heap,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> Default for PoolBuilder<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, A: GlobalAlloc> PoolBuilder<T, A> {
+    /// Should each object be on a separate CPU cache line. See `Pool::with_params`.
+    #[inline]
+    pub fn align_to_cache(mut self, align_to_cache: bool) -> Self {
+        self.align_to_cache = align_to_cache;
+        self
+    }
+
+    /// The size of an L1 cache line. Must be a power of 2. Defaults to `cache_line_size()`.
+    #[inline]
+    pub fn cache_line_size(mut self, cache_line_size: usize) -> Self {
+        self.cache_line_size = cache_line_size;
+        self
+    }
+
+    /// The number of associativity sets of the processor, which decides the size of batch
+    /// allocations. See `Pool::with_system_params`. Defaults to 64.
+    #[inline]
+    pub fn number_of_sets(mut self, number_of_sets: usize) -> Self {
+        self.number_of_sets = number_of_sets;
+        self
+    }
+
+    /// Should the `Pool` keep a bitmap of the slots in use, as required by `Pool::for_each_live`.
+    #[inline]
+    pub fn track_liveness(mut self, track_liveness: bool) -> Self {
+        self.track_liveness = track_liveness;
+        self
+    }
+
+    /// Should the `Pool` count the releases of every slot, as required by `Object::downgrade`.
+    /// Always enabled in debug builds, where the counts are used to detect `Object`s that outlived
+    /// their slot.
+    #[inline]
+    pub fn track_generations(mut self, track_generations: bool) -> Self {
+        self.track_generations = track_generations;
+        self
+    }
+
+    /// The `Pool` never grows beyond `max_slots` slots. See `Pool::with_max_capacity`.
+    #[inline]
+    pub fn max_capacity(mut self, max_slots: usize) -> Self {
+        self.max_capacity = max_slots;
+        self
+    }
+
+    /// Allocates at least `slots` free slots when the `Pool` is built, like `Pool::with_capacity`.
+    /// `build` panics if they would go beyond `max_capacity`.
+    #[inline]
+    pub fn prewarm(mut self, slots: usize) -> Self {
+        self.prewarm = slots;
+        self
+    }
+
+    /// Calls `hook` whenever a slot is returned to the `Pool`. See `Pool::with_return_hook`.
+    #[inline]
+    pub fn return_hook(mut self, hook: ReturnHook) -> Self {
+        self.return_hook = Some(hook);
+        self
+    }
+
+    /// How many batches are allocated when the `Pool` runs out of free slots. Defaults to
+    /// `GrowthPolicy::Fixed(1)`.
+    #[inline]
+    pub fn growth(mut self, growth: GrowthPolicy) -> Self {
+        self.growth = growth;
+        self
+    }
+
+    /// The order in which free slots are reused. Defaults to `FreeOrder::default()`.
+    #[inline]
+    pub fn free_order(mut self, free_order: FreeOrder) -> Self {
+        self.free_order = free_order;
+        self
+    }
+
+    /// The alignment of every slot, for types that need a larger alignment than their own, like
+    /// SIMD data. Must be a power of 2 and at least `align_of::<T>()`, which is the default.
+    #[inline]
+    pub fn min_align(mut self, min_align: usize) -> Self {
+        self.min_align = min_align;
+        self
+    }
+
+    /// What to do with batches that become empty. See `Pool::set_empty_batch_policy`. Defaults to
+    /// `EmptyBatchPolicy::Keep`.
+    #[inline]
+    pub fn empty_batch_policy(mut self, policy: EmptyBatchPolicy) -> Self {
+        self.empty_batch_policy = policy;
+        self
+    }
+
+    /// Calls `handler` when the allocation of a batch fails. See `Pool::set_oom_handler`.
+    #[inline]
+    pub fn oom_handler(mut self, handler: OomHandler) -> Self {
+        self.oom_handler = Some(handler);
+        self
+    }
+
+    /// When the `Pool` can't allocate a new batch, because it reached `max_capacity` or the
+    /// allocation failed, allocate each new value on its own instead of failing. Defaults to
+    /// `false`.
+    ///
+    /// This gives a bounded fast path and an unbounded slow path. The values allocated on their
+    /// own are freed when their `Object` is dropped, and their memory is never reused. While any
+    /// of them are alive, returning a slot takes a lock to tell them apart from the slots of the
+    /// batches.
+    #[inline]
+    pub fn overflow(mut self, overflow: bool) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Places the memory of the batches on the NUMA node `node`. See `Pool::with_numa_node`.
+    #[cfg(feature = "numa")]
+    #[inline]
+    pub fn numa_node(mut self, node: u32) -> Self {
+        self.numa_node = Some(node);
+        self
+    }
+
+    /// Allocates the batches with `allocator`.
+    #[inline]
+    pub fn allocator<B: GlobalAlloc>(self, allocator: B) -> PoolBuilder<T, B> {
+        PoolBuilder {
+            align_to_cache: self.align_to_cache,
+            cache_line_size: self.cache_line_size,
+            number_of_sets: self.number_of_sets,
+            track_liveness: self.track_liveness,
+            track_generations: self.track_generations,
+            max_capacity: self.max_capacity,
+            prewarm: self.prewarm,
+            return_hook: self.return_hook,
+            growth: self.growth,
+            free_order: self.free_order,
+            min_align: self.min_align,
+            empty_batch_policy: self.empty_batch_policy,
+            overflow: self.overflow,
+            oom_handler: self.oom_handler,
+            #[cfg(feature = "numa")]
+            numa_node: self.numa_node,
+            allocator,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Creates the `Pool`.
+    pub fn build(self) -> Pool<T, A> {
+        assert!(self.cache_line_size != 0, "Pool requested with cache_line_size = 0");
+        // otherwise a stride rounded to whole cache lines could break the alignment of the slots
+        assert!(self.cache_line_size.is_power_of_two(), "Pool requested with a cache_line_size that is not a power of 2");
+        assert!(self.number_of_sets != 0, "Pool requested with number_of_sets = 0");
+        match self.growth {
+            GrowthPolicy::Fixed(0) => panic!("Pool requested with GrowthPolicy::Fixed(0)"),
+            GrowthPolicy::Geometric(0) => panic!("Pool requested with GrowthPolicy::Geometric(0)"),
+            _ => {}
+        }
+        assert!(self.min_align.is_power_of_two(), "Pool requested with a min_align that is not a power of 2");
+        assert!(self.min_align >= mem::align_of::<T>(), "Pool requested with a min_align below the alignment of the type");
+        let cache_line_size = self.cache_line_size;
+        let batch_alignment = cache_line_size.max(self.min_align);
+        // only whole pages can be placed on a node
+        #[cfg(all(feature = "numa", target_os = "linux"))]
+        let batch_alignment = if self.numa_node.is_some() {
+            batch_alignment.max(page_size())
+        } else {
+            batch_alignment
+        };
+        let align = mem::size_of::<T>()
+            .div_ceil(self.min_align)
+            .checked_mul(self.min_align)
+            .expect("Pool requested with a slot size that overflows usize");
+        let stride = if self.align_to_cache {
+            align.div_ceil(cache_line_size)
+                .checked_mul(cache_line_size)
+                .expect("Pool requested with a slot size that overflows usize")
+        } else {
+            align
+        };
+        debug_assert!(stride.is_multiple_of(mem::align_of::<T>()));
+        // zero-sized types never allocate, so they don't have batches
+        let batch = if mem::size_of::<T>() == 0 {
+            0
+        } else {
+            let sets_size = self.number_of_sets
+                .checked_mul(cache_line_size)
+                .expect("Pool requested with number_of_sets * cache_line_size overflowing usize");
+            (sets_size / stride).max(1)
+        };
+        let mem_size = batch.checked_mul(stride).expect("Pool requested with a batch size that overflows usize");
+        let layout = Layout::from_size_align(mem_size, batch_alignment).expect("Pool requested with bad system cache parameters");
+        let mut pool = Pool {
+            allocator: self.allocator,
+            id: NEXT_POOL_ID.fetch_add(1, Ordering::Relaxed),
+            data: Mutex::new(Vec::new()),
+            free: FreeSlots::new(self.free_order == FreeOrder::Lifo),
+            spilled: Arc::new(Mutex::new(Vec::new())),
+            layout,
+            batch,
+            stride,
+            capacity: AtomicUsize::new(0),
+            max_capacity: self.max_capacity,
+            live: AtomicUsize::new(0),
+            liveness: if self.track_liveness {
+                Some(RwLock::new(Vec::new()))
+            } else {
+                None
+            },
+            generations: if self.track_generations || cfg!(debug_assertions) {
+                Some(RwLock::new(Vec::new()))
+            } else {
+                None
+            },
+            next_generation: AtomicUsize::new(1),
+            return_hook: self.return_hook,
+            growth: self.growth,
+            growth_step: AtomicUsize::new(match self.growth {
+                GrowthPolicy::Fixed(n) => n,
+                GrowthPolicy::Geometric(_) => 1,
+            }),
+            batch_use: match self.empty_batch_policy {
+                EmptyBatchPolicy::Keep => None,
+                _ => Some(RwLock::new(Vec::new())),
+            },
+            empty_batch_policy: self.empty_batch_policy,
+            overflow: None,
+            oom_handler: self.oom_handler,
+            #[cfg(feature = "numa")]
+            numa_node: self.numa_node,
+            _marker: PhantomData,
+        };
+        if self.overflow {
+            // the values allocated on their own fit any use of a slot
+            pool.overflow = Some(Overflow {
+                layout: Layout::from_size_align(stride, pool.slot_alignment()).unwrap(),
+                slots: Mutex::new(Vec::new()),
+                count: AtomicUsize::new(0),
+            });
+        }
+        if self.prewarm != 0 {
+            pool.reserve(self.prewarm);
+        }
+        pool
+    }
 }
 
 impl<T> Pool<T> {
@@ -114,46 +730,120 @@ impl<T> Pool<T> {
     /// Creates a new `Pool`.
     ///
     /// - `align_to_cache`: Should each object be on a separate CPU cache line. Speeds up
-    /// multithreaded usage, but hurts single-threaded cache locality a bit and requires a bit more memory.
-    /// Has no effect if `size_of::<T>` is already a multiple of a cache line size.
+    ///   multithreaded usage, but hurts single-threaded cache locality a bit and requires a bit more memory.
+    ///   Has no effect if `size_of::<T>` is already a multiple of a cache line size.
+    ///
+    /// The cache line size is given by `cache_line_size()`. Use `with_system_params` to set it
+    /// manually.
     #[inline]
     pub fn with_params(align_to_cache: bool) -> Pool<T> {
-        Pool::with_system_params(align_to_cache, 64, 64)
+        PoolBuilder::new().align_to_cache(align_to_cache).build()
+    }
+
+    /// Creates a new `Pool` with at least `capacity` free slots already allocated.
+    ///
+    /// Memory is allocated in whole batches, so the actual capacity is rounded up to a multiple of
+    /// the batch size. `capacity()` will report the rounded value.
+    #[inline]
+    pub fn with_capacity(capacity: usize) -> Pool<T> {
+        Pool::with_capacity_and_params(capacity, false)
+    }
+
+    /// Creates a new `Pool` with at least `capacity` free slots already allocated.
+    ///
+    /// Memory is allocated in whole batches, so the actual capacity is rounded up to a multiple of
+    /// the batch size. `capacity()` will report the rounded value.
+    ///
+    /// - `align_to_cache`: Same as in `with_params`.
+    #[inline]
+    pub fn with_capacity_and_params(capacity: usize, align_to_cache: bool) -> Pool<T> {
+        PoolBuilder::new().align_to_cache(align_to_cache).prewarm(capacity).build()
+    }
+
+    /// Creates a new `Pool` that never grows beyond `max_slots` slots.
+    ///
+    /// A batch that would take the capacity beyond `max_slots` is never allocated, so the actual
+    /// limit is `max_slots` rounded down to a multiple of the batch size. When the limit is reached,
+    /// `try_push` gives the object back and `push` panics. `PoolBuilder::overflow` allocates the
+    /// values beyond the limit on their own instead.
+    #[inline]
+    pub fn with_max_capacity(max_slots: usize) -> Pool<T> {
+        PoolBuilder::new().max_capacity(max_slots).build()
+    }
+
+    /// Creates a new `Pool` whose batches are placed on the NUMA node `node`, for threads pinned to
+    /// that node.
+    ///
+    /// It is a best-effort hint: on Linux every new batch is page-aligned and bound to the node
+    /// with `mbind`, preferring the node but falling back to others when it runs out of memory.
+    /// Invalid nodes and errors are ignored. On other platforms it does nothing.
+    #[cfg(feature = "numa")]
+    #[inline]
+    pub fn with_numa_node(node: u32) -> Pool<T> {
+        PoolBuilder::new().numa_node(node).build()
+    }
+
+    /// Creates a new `Pool` that calls `hook` with the slot of every value returned to it, for
+    /// debugging the lifetimes of objects.
+    ///
+    /// The hook is called after the slot is free, when an `Object` is dropped or recovered and when
+    /// `return_slots` returns detached slots. It is not called for the objects still alive when the
+    /// `Pool` is dropped. By then the slot might already hold a new value, so the pointer should
+    /// only be used to identify it.
+    #[inline]
+    pub fn with_return_hook(hook: ReturnHook) -> Pool<T> {
+        PoolBuilder::new().return_hook(hook).build()
     }
 
     /// Creates a new `Pool`.
     ///
     /// - `align_to_cache`: Should each object be on a separate CPU cache line. Speeds up
-    /// multithreaded usage, but hurts single-threaded cache locality a bit and requires a bit more memory.
-    /// Has no effect if `size_of::<T>` is already a multiple of a cache line size.
+    ///   multithreaded usage, but hurts single-threaded cache locality a bit and requires a bit more memory.
+    ///   Has no effect if `size_of::<T>` is already a multiple of a cache line size.
     /// 
     /// - `cache_line_size`: The size of an L1 cache line on the architecture. Must be a power of 2.
     /// 
     /// - `number_of_sets`: The number of [associativity](https://en.wikipedia.org/wiki/CPU_cache#Associativity) sets
-    /// of the target processor. Decides the size of batch allocations.
-    #[inline]
-    pub fn with_system_params(align_to_cache: bool, cache_line_size: usize, number_of_sets: usize) -> Pool<T> {
-        assert!(cache_line_size != 0, "Pool requested with cache_line_size = 0");
-        assert!(number_of_sets != 0, "Pool requested with number_of_sets = 0");
-        assert!(mem::size_of::<T>() != 0,
-                "Pool requested with type of size 0");
-        let batch_alignment = cache_line_size.max(mem::align_of::<T>());
-        let align = ((mem::size_of::<T>() + mem::align_of::<T>() - 1) / mem::align_of::<T>()) * mem::align_of::<T>();
-        let stride = if align_to_cache {
-            ((cache_line_size + align - 1) / cache_line_size) * cache_line_size
-        } else {
-            align
-        };
-        let batch = (number_of_sets * cache_line_size / stride).max(1);
-        let mem_size = batch * stride;
-        let layout = Layout::from_size_align(mem_size, batch_alignment).expect("Pool requested with bad system cache parameters");
-        Pool {
-            data: Mutex::new(Vec::new()),
-            free: MsQueue::new(),
-            layout,
-            batch,
-            stride,
-        }
+    ///   of the target processor. Decides the size of batch allocations.
+    ///
+    /// - `track_liveness`: Should the `Pool` keep a bitmap of the slots in use, as required by
+    ///   `for_each_live`. Makes every allocation and deallocation take a shared lock.
+    ///
+    /// `PoolBuilder` sets the same parameters by name.
+    #[inline]
+    pub fn with_system_params(align_to_cache: bool,
+                              cache_line_size: usize,
+                              number_of_sets: usize,
+                              track_liveness: bool)
+                              -> Pool<T> {
+        Pool::with_system_params_and_allocator(align_to_cache, cache_line_size, number_of_sets, track_liveness, Heap)
+    }
+}
+
+impl<T, A: GlobalAlloc> Pool<T, A> {
+    /// Creates a new `Pool` that allocates its batches with `allocator`.
+    #[inline]
+    pub fn with_allocator(allocator: A) -> Pool<T, A> {
+        PoolBuilder::new().allocator(allocator).build()
+    }
+
+    /// Creates a new `Pool` that allocates its batches with `allocator`.
+    ///
+    /// The rest of the parameters are the same as in `with_system_params`.
+    #[inline]
+    pub fn with_system_params_and_allocator(align_to_cache: bool,
+                                            cache_line_size: usize,
+                                            number_of_sets: usize,
+                                            track_liveness: bool,
+                                            allocator: A)
+                                            -> Pool<T, A> {
+        PoolBuilder::new()
+            .align_to_cache(align_to_cache)
+            .cache_line_size(cache_line_size)
+            .number_of_sets(number_of_sets)
+            .track_liveness(track_liveness)
+            .allocator(allocator)
+            .build()
     }
 
     /// Save the object on the heap. Will get a pointer that will drop it's content when
@@ -164,159 +854,3868 @@ impl<T> Pool<T> {
     ///
     /// Will panic if out of memory.
     #[inline]
-    pub fn push(&self, obj: T) -> Object<T> {
-        let slot = match self.free.try_pop() {
+    pub fn push(&self, obj: T) -> Object<'_, T, A> {
+        match self.try_push(obj) {
+            Ok(x) => x,
+            Err(_) => panic!("Pool failed to allocate a new batch"),
+        }
+    }
+
+    /// Save the object on the heap, like `push`.
+    ///
+    /// If the `Pool` needs to allocate a new batch and the allocation fails, or the `Pool` would
+    /// grow beyond its maximum capacity, the object is returned back in the `Err` variant instead of
+    /// panicking.
+    #[inline]
+    pub fn try_push(&self, obj: T) -> Result<Object<'_, T, A>, T> {
+        let slot = match self.take_slot() {
             Some(x) => x,
-            None => self.expand(),
+            None => return Err(obj),
         };
         unsafe {
             ptr::write(slot, obj);
         }
-        Object {
-            obj: slot,
-            manager: self,
-        }
+        Ok(Object::from_slot(slot, self))
     }
 
+    /// Save the object returned by `f` on the heap, like `push`.
+    ///
+    /// The slot is taken before `f` is called, so the value can be written directly into it instead
+    /// of being moved there. If `f` panics, the slot is returned to the `Pool`.
+    ///
+    /// Will panic if out of memory.
     #[inline]
-    fn expand(&self) -> *mut T {
-        unsafe {
-            let mut lock = self.data.lock().unwrap();
-            if let Some(x) = self.free.try_pop() {
-                return x;
-            }
-            let extra = Heap::default().alloc(self.layout.clone()).unwrap() as *mut T;
-            // starting from 1 since index 0 will be returned
-            for i in 1..self.batch {
-                self.free.push((extra as usize + i * self.stride) as *mut T);
-            }
-            lock.push(extra);
-            extra
-        }
+    pub fn push_with<F>(&self, f: F) -> Object<'_, T, A>
+        where F: FnOnce() -> T
+    {
+        unsafe { self.push_in_place(|slot| ptr::write(slot, f())) }
     }
 
+    /// Save the default value of `T` on the heap, like `push(T::default())`.
+    ///
+    /// Will panic if out of memory.
     #[inline]
-    fn ret_ptr(&self, obj: *mut T) {
-        self.free.push(obj);
+    pub fn push_default(&self) -> Object<'_, T, A>
+        where T: Default
+    {
+        self.push_with(T::default)
     }
-}
 
-impl<T> Default for Pool<T> {
-    fn default() -> Self {
-        Self::new()
+    /// Initializes an object directly in a slot of the `Pool`. `f` receives a pointer to the
+    /// uninitialized slot, properly aligned and valid for writes of a `T`. If `f` panics, the slot is
+    /// returned to the `Pool` without dropping its content.
+    ///
+    /// Will panic if out of memory.
+    ///
+    /// # Safety
+    ///
+    /// `f` must fully initialize the slot with a valid `T` before it returns. The returned `Object`
+    /// will treat the slot as a valid value.
+    #[inline]
+    pub unsafe fn push_in_place<F>(&self, f: F) -> Object<'_, T, A>
+        where F: FnOnce(*mut T)
+    {
+        let slot = match self.take_slot() {
+            Some(x) => x,
+            None => panic!("Pool failed to allocate a new batch"),
+        };
+        let guard = SlotGuard {
+            slot,
+            pool: self,
+        };
+        f(slot);
+        mem::forget(guard);
+        Object::from_slot(slot, self)
     }
-}
 
-impl<T> Drop for Pool<T> {
-    #[inline]
-    fn drop(&mut self) {
-        let lock = match self.data.lock() {
-            Ok(guard) => guard,
-            Err(poisoned) => poisoned.into_inner(),
+    /// Moves a boxed value into the `Pool`, like `push`, and frees the memory of the `Box`.
+    ///
+    /// The value is copied directly from the `Box` into its slot, without moving it through the
+    /// stack first.
+    ///
+    /// Will panic if out of memory.
+    pub fn adopt(&self, boxed: Box<T>) -> Object<'_, T, A> {
+        let slot = match self.take_slot() {
+            Some(x) => x,
+            None => panic!("Pool failed to allocate a new batch"),
         };
-        for block in lock.deref() {
-            unsafe {
-                Heap::default().dealloc(*block as *mut u8, self.layout.clone());
-            }
+        unsafe {
+            let raw = Box::into_raw(boxed);
+            ptr::copy_nonoverlapping(raw, slot, 1);
+            // frees the memory without dropping the value, which is owned by the slot now
+            drop(Box::from_raw(raw as *mut mem::ManuallyDrop<T>));
         }
+        Object::from_slot(slot, self)
     }
-}
 
-impl<'active, T> Object<'active, T> {
-    /// Returns the owned object from the pool-allocated memory.
-    #[allow(needless_pass_by_value)]
-    #[inline]
-    pub fn recover(t: Self) -> T {
+    /// Save all the objects of `iter` on the heap, like `push`. The returned `Object`s are in the
+    /// same order as the values of `iter`.
+    ///
+    /// If there are not enough free slots for all the objects, the lock is taken once and as many
+    /// batches as needed are allocated, instead of taking it again for every batch.
+    ///
+    /// Will panic if out of memory.
+    pub fn push_batch<I>(&self, iter: I) -> Vec<Object<'_, T, A>>
+        where I: IntoIterator<Item = T>
+    {
+        let values: Vec<T> = iter.into_iter().collect();
+        let slots = self.take_slots(values.len()).expect("Pool failed to allocate a new batch");
+        values.into_iter()
+            .zip(slots)
+            .map(|(obj, slot)| {
+                unsafe {
+                    ptr::write(slot, obj);
+                }
+                Object::from_slot(slot, self)
+            })
+            .collect()
+    }
+
+    /// Creates an `Object` owning the value at `ptr`, like `Box::from_raw`. Reverses
+    /// `Object::into_raw`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `Object::into_raw` for an `Object` of this `Pool`, and no
+    /// other `Object` may have been created for it since.
+    #[inline]
+    pub unsafe fn from_raw(&self, ptr: *mut T) -> Object<'_, T, A> {
+        Object::from_slot(ptr, self)
+    }
+
+    /// Replaces the value of `o` with `new`, keeping its slot, instead of dropping `o` and pushing
+    /// `new`. The old value is dropped in place. The slot never goes through the free list, so the
+    /// return hook and the empty batch policy are not involved.
+    ///
+    /// Panics if `o` was not allocated from this `Pool`.
+    #[inline]
+    pub fn recycle_into<'a>(&'a self, mut o: Object<'a, T, A>, new: T) -> Object<'a, T, A> {
+        assert!(ptr::eq(o.manager, self), "Object recycled into a different Pool");
+        *o = new;
+        o
+    }
+
+    /// Copies all the values of `items` on the heap, like `push_batch`. The slots for all of them are
+    /// taken at once, then each value is copied directly into its slot.
+    ///
+    /// Will panic if out of memory.
+    pub fn push_slice(&self, items: &[T]) -> Vec<Object<'_, T, A>>
+        where T: Copy
+    {
+        let slots = self.take_slots(items.len()).expect("Pool failed to allocate a new batch");
+        items.iter()
+            .zip(slots)
+            .map(|(obj, slot)| {
+                unsafe {
+                    ptr::copy_nonoverlapping(obj, slot, 1);
+                }
+                Object::from_slot(slot, self)
+            })
+            .collect()
+    }
+
+    /// Takes `count` slots for new objects, allocating new batches under a single lock if needed.
+    /// If an allocation fails, the slots that were already taken are returned.
+    fn take_slots(&self, count: usize) -> Option<Vec<*mut T>> {
+        let mut slots = Vec::with_capacity(count);
+        if mem::size_of::<T>() == 0 {
+            slots.resize(count, ptr::NonNull::dangling().as_ptr());
+        } else {
+            while slots.len() < count {
+                match self.pop_free() {
+                    Some(x) => slots.push(x),
+                    None => break,
+                }
+            }
+            if slots.len() < count {
+                let mut lock = self.data.lock();
+                {
+                    let mut spilled = self.spilled.lock();
+                    let start = spilled.len() - (count - slots.len()).min(spilled.len());
+                    slots.extend(spilled.drain(start..).map(|x| x as *mut T));
+                }
+                while slots.len() < count {
+                    if let Some(x) = self.free.try_pop() {
+                        slots.push(x as *mut T);
+                        continue;
+                    }
+                    match self.alloc_block(&mut lock) {
+                        Some(block) => {
+                            // the slots are taken directly, only the rest go through `free`
+                            let taken = (count - slots.len()).min(self.batch);
+                            for i in 0..self.batch {
+                                let slot = block as usize + i * self.stride;
+                                if i < taken {
+                                    slots.push(slot as *mut T);
+                                } else {
+                                    self.free.push(slot);
+                                }
+                            }
+                        }
+                        None if self.overflow.is_some() => break,
+                        None => {
+                            for slot in slots {
+                                self.free.push(slot as usize);
+                            }
+                            return None;
+                        }
+                    }
+                }
+            }
+        }
+        let taken = slots.len();
+        while slots.len() < count {
+            match self.alloc_overflow() {
+                Some(x) => slots.push(x),
+                None => {
+                    for slot in slots.drain(taken..) {
+                        self.free_overflow(slot);
+                    }
+                    for slot in slots {
+                        self.free.push(slot as usize);
+                    }
+                    return None;
+                }
+            }
+        }
+        self.live.fetch_add(count, Ordering::Relaxed);
+        for slot in &slots[..taken] {
+            self.mark_live(*slot, true);
+            self.count_use(*slot, true);
+        }
+        Some(slots)
+    }
+
+    /// Takes a slot for a new object, allocating a new batch if needed.
+    #[inline]
+    fn take_slot(&self) -> Option<*mut T> {
+        let slot = if mem::size_of::<T>() == 0 {
+            // zero-sized types need no memory, any aligned pointer will do
+            ptr::NonNull::dangling().as_ptr()
+        } else {
+            match self.pop_free() {
+                Some(x) => x,
+                None => {
+                    match self.try_expand() {
+                        Some(x) => x,
+                        None => {
+                            let slot = self.alloc_overflow()?;
+                            self.live.fetch_add(1, Ordering::Relaxed);
+                            return Some(slot);
+                        }
+                    }
+                }
+            }
+        };
+        self.live.fetch_add(1, Ordering::Relaxed);
+        self.mark_live(slot, true);
+        self.count_use(slot, true);
+        Some(slot)
+    }
+
+    /// Allocates a slot on its own, if the `Pool` was created with `overflow`.
+    fn alloc_overflow(&self) -> Option<*mut T> {
+        let overflow = self.overflow.as_ref()?;
+        let slot = unsafe { self.allocator.alloc(overflow.layout) } as *mut T;
+        if slot.is_null() {
+            return None;
+        }
+        let generation = match self.generations {
+            Some(_) => self.next_generation.fetch_add(1, Ordering::Relaxed),
+            None => 0,
+        };
+        let mut slots = overflow.slots.lock();
+        let index = slots.binary_search_by_key(&(slot as usize), |s| s.0).unwrap_err();
+        slots.insert(index, (slot as usize, generation));
+        overflow.count.fetch_add(1, Ordering::Relaxed);
+        Some(slot)
+    }
+
+    /// Frees a slot allocated by `alloc_overflow`.
+    fn free_overflow(&self, slot: *mut T) {
+        let overflow = self.overflow.as_ref().unwrap();
+        {
+            let mut slots = overflow.slots.lock();
+            let index = slots.binary_search_by_key(&(slot as usize), |s| s.0).unwrap();
+            slots.remove(index);
+            overflow.count.fetch_sub(1, Ordering::Relaxed);
+        }
+        unsafe {
+            self.allocator.dealloc(slot as *mut u8, overflow.layout);
+        }
+    }
+
+    /// Returns `true` if `slot` was allocated on its own by `alloc_overflow`. Only takes the lock
+    /// while there are such slots.
+    #[inline]
+    fn is_overflow(&self, slot: *mut T) -> bool {
+        match self.overflow {
+            Some(ref overflow) if overflow.count.load(Ordering::Relaxed) != 0 => {
+                overflow.slots.lock().binary_search_by_key(&(slot as usize), |s| s.0).is_ok()
+            }
+            _ => false,
+        }
+    }
+
+    /// Takes a slot when the free list is empty, allocating new batches if there is still none
+    /// after waiting for the lock. The number of batches is decided by the growth policy. They are
+    /// allocated without holding the lock, so threads that need new batches at the same time don't
+    /// wait for each other's allocations.
+    #[inline]
+    fn try_expand(&self) -> Option<*mut T> {
+        {
+            let _lock = self.data.lock();
+            if let Some(x) = self.free.try_pop() {
+                return Some(x as *mut T);
+            }
+            let mut spilled = self.spilled.lock();
+            if let Some(x) = spilled.pop() {
+                for slot in spilled.drain(..) {
+                    self.free.push(slot);
+                }
+                return Some(x as *mut T);
+            }
+        }
+        if !self.claim_batch() {
+            return None;
+        }
+        let block = self.alloc_claimed()?;
+        // more batches, as decided by the growth policy
+        let mut extra = Vec::new();
+        for _ in 1..self.growth_step.load(Ordering::Relaxed) {
+            if !self.claim_batch() {
+                break;
+            }
+            match self.alloc_claimed() {
+                Some(x) => extra.push(x),
+                None => break,
+            }
+        }
+        {
+            let mut lock = self.data.lock();
+            self.add_block(&mut lock, block);
+            for x in &extra {
+                self.add_block(&mut lock, *x);
+            }
+        }
+        // starting from 1 since index 0 will be returned
+        for i in 1..self.batch {
+            self.free.push(block as usize + i * self.stride);
+        }
+        for x in extra {
+            for i in 0..self.batch {
+                self.free.push(x as usize + i * self.stride);
+            }
+        }
+        if let GrowthPolicy::Geometric(factor) = self.growth {
+            let step = self.growth_step.load(Ordering::Relaxed);
+            self.growth_step.store(step.saturating_mul(factor).min(MAX_GROWTH_BATCHES), Ordering::Relaxed);
+        }
+        Some(block)
+    }
+
+    /// Allocates a new batch. The first slot is returned and the rest are added to `free`.
+    /// `data` is the locked content of `self.data`.
+    #[inline]
+    fn alloc_batch(&self, data: &mut Vec<*const u8>) -> Option<*mut T> {
+        let extra = self.alloc_block(data)?;
+        // starting from 1 since index 0 will be returned
+        for i in 1..self.batch {
+            self.free.push(extra as usize + i * self.stride);
+        }
+        Some(extra)
+    }
+
+    /// Allocates a new batch without adding any of its slots to `free`.
+    /// `data` is the locked content of `self.data`.
+    #[inline]
+    fn alloc_block(&self, data: &mut Vec<*const u8>) -> Option<*mut T> {
+        if !self.claim_batch() {
+            return None;
+        }
+        let block = self.alloc_claimed()?;
+        self.add_block(data, block);
+        Some(block)
+    }
+
+    /// Adds a batch to `capacity` before it is allocated, so that threads allocating at the same
+    /// time can't go over `max_capacity` together. Returns `false` if the batch doesn't fit.
+    #[inline]
+    fn claim_batch(&self) -> bool {
+        let mut capacity = self.capacity();
+        loop {
+            if self.max_capacity - capacity < self.batch {
+                return false;
+            }
+            match self.capacity.compare_exchange_weak(
+                capacity,
+                capacity + self.batch,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(x) => capacity = x,
+            }
+        }
+    }
+
+    /// Allocates the memory of a batch claimed by `claim_batch`, giving the claim back if the
+    /// allocation fails.
+    #[inline]
+    fn alloc_claimed(&self) -> Option<*mut T> {
+        let block = loop {
+            let block = unsafe { self.allocator.alloc(self.layout) as *mut T };
+            if !block.is_null() {
+                break block;
+            }
+            match self.oom_handler {
+                Some(ref handler) if handler() == OomAction::Retry => {}
+                _ => {
+                    self.capacity.fetch_sub(self.batch, Ordering::Relaxed);
+                    return None;
+                }
+            }
+        };
+        #[cfg(feature = "numa")]
+        {
+            if let Some(node) = self.numa_node {
+                bind_to_node(block as *mut u8, self.layout.size(), node);
+            }
+        }
+        Some(block)
+    }
+
+    /// Records a newly allocated batch. `data` is the locked content of `self.data`.
+    fn add_block(&self, data: &mut Vec<*const u8>, block: *mut T) {
+        data.push(block as *const u8);
+        if let Some(ref generations) = self.generations {
+            // the slots of a batch only need to differ from the slots that had the same address
+            // before, so they can share a generation
+            let generation = self.next_generation.fetch_add(1, Ordering::Relaxed);
+            let mut generations = generations.write();
+            let index = generations.binary_search_by_key(&(block as usize), |b| b.start).unwrap_err();
+            generations.insert(index, GenerationBlock {
+                start: block as usize,
+                generations: (0..self.batch).map(|_| AtomicUsize::new(generation)).collect(),
+            });
+        }
+        if let Some(ref liveness) = self.liveness {
+            let mut liveness = liveness.write();
+            let index = liveness.binary_search_by_key(&(block as usize), |b| b.start).unwrap_err();
+            let words = self.batch.div_ceil(USIZE_BITS);
+            liveness.insert(index, LiveBlock {
+                start: block as usize,
+                bits: (0..words).map(|_| AtomicUsize::new(0)).collect(),
+            });
+        }
+        if let Some(ref batch_use) = self.batch_use {
+            let mut batch_use = batch_use.write();
+            let index = batch_use.binary_search_by_key(&(block as usize), |b| b.start).unwrap_err();
+            batch_use.insert(index, BatchUse {
+                start: block as usize,
+                used: AtomicUsize::new(0),
+            });
+        }
+    }
+
+    /// Returns the generation of `slot`, which changes every time the slot is released and is never
+    /// given twice by the same `Pool`. If `release` is `true`, the slot is being released and gets
+    /// a new generation first.
+    ///
+    /// Returns 0 if the `Pool` doesn't track generations, for zero-sized types, and for addresses
+    /// that are not slots of the `Pool` anymore.
+    fn generation(&self, slot: *mut T, release: bool) -> usize {
+        let generations = match self.generations {
+            Some(ref x) if mem::size_of::<T>() != 0 => x,
+            _ => return 0,
+        };
+        match self.overflow {
+            Some(ref overflow) if overflow.count.load(Ordering::Relaxed) != 0 => {
+                // the values allocated on their own get their generation when allocated, and free
+                // their memory instead of being released
+                let slots = overflow.slots.lock();
+                if let Ok(i) = slots.binary_search_by_key(&(slot as usize), |s| s.0) {
+                    return slots[i].1;
+                }
+            }
+            _ => {}
+        }
+        let generations = generations.read();
+        let index = match generations.binary_search_by_key(&(slot as usize), |b| b.start) {
+            Ok(i) => i,
+            Err(0) => return 0,
+            Err(i) => i - 1,
+        };
+        let block = &generations[index];
+        let generation = match block.generations.get((slot as usize - block.start) / self.stride) {
+            Some(x) => x,
+            None => return 0,
+        };
+        if release {
+            let new = self.next_generation.fetch_add(1, Ordering::Relaxed);
+            generation.store(new, Ordering::Relaxed);
+            new
+        } else {
+            generation.load(Ordering::Relaxed)
+        }
+    }
+
+    /// Marks `slot` as used or free in the liveness bitmap, if the `Pool` keeps one.
+    #[inline]
+    fn mark_live(&self, slot: *mut T, live: bool) {
+        let liveness = match self.liveness {
+            Some(ref x) if mem::size_of::<T>() != 0 => x.read(),
+            _ => return,
+        };
+        let index = match liveness.binary_search_by_key(&(slot as usize), |b| b.start) {
+            Ok(i) => i,
+            Err(i) => i - 1,
+        };
+        let block = &liveness[index];
+        let bit = (slot as usize - block.start) / self.stride;
+        let word = &block.bits[bit / USIZE_BITS];
+        let mask = 1 << (bit % USIZE_BITS);
+        if live {
+            word.fetch_or(mask, Ordering::Relaxed);
+        } else {
+            word.fetch_and(!mask, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts `slot` as used or returned in its batch, if the `Pool` releases empty batches. Returns
+    /// the start of the batch if its last used slot was returned.
+    #[inline]
+    fn count_use(&self, slot: *mut T, used: bool) -> Option<usize> {
+        let batch_use = match self.batch_use {
+            Some(ref x) if mem::size_of::<T>() != 0 => x.read(),
+            _ => return None,
+        };
+        let index = match batch_use.binary_search_by_key(&(slot as usize), |b| b.start) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+        let block = &batch_use[index];
+        if slot as usize >= block.start + self.batch * self.stride {
+            return None;
+        }
+        if used {
+            block.used.fetch_add(1, Ordering::Relaxed);
+            None
+        } else if block.used.fetch_sub(1, Ordering::Relaxed) == 1 {
+            Some(block.start)
+        } else {
+            None
+        }
+    }
+
+    /// Applies the empty batch policy to the batch starting at `block`, which has no used slots.
+    fn empty_batch(&self, block: usize) {
+        let release = match self.empty_batch_policy {
+            EmptyBatchPolicy::Keep => false,
+            EmptyBatchPolicy::Release => true,
+            EmptyBatchPolicy::Callback(ref f) => f(&self.stats()),
+        };
+        if release {
+            self.release_empty(Some(block));
+        }
+    }
+
+    /// Sets a function called when the allocation of a batch fails, before giving up. It can free
+    /// some memory, like caches, and return `OomAction::Retry` to try the allocation again, which
+    /// calls it again if the allocation still fails. `OomAction::Abort` gives up like without a
+    /// handler.
+    ///
+    /// The function may be called while the lock of the `Pool` is held, so it must not use the
+    /// `Pool`. Reaching `max_capacity` is not an allocation failure and doesn't call it.
+    pub fn set_oom_handler<F>(&mut self, handler: F)
+        where F: Fn() -> OomAction + Send + Sync + 'static
+    {
+        self.oom_handler = Some(Box::new(handler));
+    }
+
+    /// Sets what the `Pool` does with a batch when all of its slots become free. See
+    /// `EmptyBatchPolicy`.
+    ///
+    /// Only batches that become empty later are affected. Use `shrink_to_fit` to release the ones
+    /// that are empty already.
+    pub fn set_empty_batch_policy(&mut self, policy: EmptyBatchPolicy) {
+        if let EmptyBatchPolicy::Keep = policy {
+            self.batch_use = None;
+        } else if self.batch_use.is_none() {
+            // every slot that is not in the shared free list is counted as used
+            self.spill_local();
+            let mut free = Vec::new();
+            while let Some(x) = self.free.try_pop() {
+                free.push(x);
+            }
+            free.extend(self.spilled.lock().drain(..));
+            let mut blocks: Vec<(usize, usize)> = self.data.lock().iter().map(|b| (*b as usize, 0)).collect();
+            blocks.sort();
+            for x in free {
+                let index = block_index(&blocks, x);
+                blocks[index].1 += 1;
+                self.free.push(x);
+            }
+            let batch = self.batch;
+            self.batch_use = Some(RwLock::new(blocks.into_iter()
+                .map(|(start, free)| {
+                    BatchUse {
+                        start,
+                        used: AtomicUsize::new(batch - free),
+                    }
+                })
+                .collect()));
+        }
+        self.empty_batch_policy = policy;
+    }
+
+    /// Calls `f` on every live value allocated from this `Pool`, including leaked ones.
+    ///
+    /// Panics if the `Pool` was not created with `track_liveness`.
+    ///
+    /// # Safety
+    ///
+    /// `f` receives mutable references to values owned by `Object`s, so no `Object` of this `Pool`
+    /// may be accessed, and no other method of the `Pool` may be called, until `for_each_live`
+    /// returns. This includes other threads and `f` itself.
+    pub unsafe fn for_each_live<F>(&self, mut f: F)
+        where F: FnMut(&mut T)
+    {
+        let liveness = self.liveness
+            .as_ref()
+            .expect("Pool::for_each_live requires a Pool created with track_liveness")
+            .read();
+        if mem::size_of::<T>() == 0 {
+            for _ in 0..self.len() {
+                f(&mut *ptr::NonNull::dangling().as_ptr());
+            }
+            return;
+        }
+        for block in liveness.iter() {
+            for (w, word) in block.bits.iter().enumerate() {
+                let mut bits = word.load(Ordering::Relaxed);
+                while bits != 0 {
+                    let bit = w * USIZE_BITS + bits.trailing_zeros() as usize;
+                    bits &= bits - 1;
+                    f(&mut *((block.start + bit * self.stride) as *mut T));
+                }
+            }
+        }
+        if let Some(ref overflow) = self.overflow {
+            for &(slot, _) in overflow.slots.lock().iter() {
+                f(&mut *(slot as *mut T));
+            }
+        }
+    }
+
+    /// Returns the number of free slots in each batch, in the order the batches were allocated.
+    /// Shows how fragmented the `Pool` is, for tuning its parameters. Takes `O(capacity)` time.
+    ///
+    /// Slots are counted as free when they hold no value, including the slots kept by other
+    /// threads for their own allocations and detached slots.
+    ///
+    /// Panics if the `Pool` was not created with `track_liveness`.
+    #[cfg(feature = "diagnostics")]
+    pub fn free_distribution(&self) -> Vec<usize> {
+        let data = self.data.lock();
+        let liveness = self.liveness
+            .as_ref()
+            .expect("Pool::free_distribution requires a Pool created with track_liveness")
+            .read();
+        data.iter()
+            .map(|b| {
+                let index = liveness.binary_search_by_key(&(*b as usize), |l| l.start).unwrap();
+                let live: u32 = liveness[index].bits
+                    .iter()
+                    .map(|w| w.load(Ordering::Relaxed).count_ones())
+                    .sum();
+                self.batch - live as usize
+            })
+            .collect()
+    }
+
+    /// Makes sure there are at least `additional` free slots beyond the live objects, allocating
+    /// new batches under a single lock if needed. Does nothing if there are enough already.
+    ///
+    /// The free slots are moved to the shared free list, so the next allocations don't need the
+    /// lock. Slots kept by other threads for their own allocations are counted as free.
+    ///
+    /// `push` takes a free slot before trying anything else, so pushes after a `reserve` already
+    /// skip the path that allocates new batches.
+    ///
+    /// Will panic if out of memory.
+    pub fn reserve(&self, additional: usize) {
+        if mem::size_of::<T>() == 0 {
+            return;
+        }
+        let mut lock = self.data.lock();
+        for slot in self.spilled.lock().drain(..) {
+            self.free.push(slot);
+        }
+        while self.capacity().saturating_sub(self.len()) < additional {
+            let first = self.alloc_batch(&mut lock).expect("Pool failed to allocate a new batch");
+            self.free.push(first as usize);
+        }
+    }
+
+    /// Releases the memory of batches that have no live `Object`s in them.
+    ///
+    /// Takes the lock and temporarily empties the free list to count the free slots of every
+    /// batch, so it is `O(capacity)`. Can be called while other threads use the `Pool`: slots that
+    /// are taken or returned during the call keep their batch from being released, and allocations
+    /// that find the free list empty will wait for the lock instead of allocating a new batch.
+    ///
+    /// Slots kept by other threads for their own allocations are not free from the point of view of
+    /// this method. The calling thread's slots are returned to the shared free list first.
+    pub fn shrink_to_fit(&self) {
+        self.release_empty(None);
+    }
+
+    /// Releases the memory of batches that have no live `Object`s in them, like `shrink_to_fit`,
+    /// returning the number of bytes released. Meant to be called regularly, like at the end of
+    /// every frame.
+    ///
+    /// Returns right away, without taking the lock, when there are not enough free slots to fill a
+    /// whole batch. With an `EmptyBatchPolicy` other than `Keep`, it also returns right away when
+    /// no batch is empty. Otherwise it takes `O(capacity)` time like `shrink_to_fit`.
+    pub fn gc(&self) -> usize {
+        if mem::size_of::<T>() == 0 || self.free_slots() < self.batch {
+            return 0;
+        }
+        if let Some(ref batch_use) = self.batch_use {
+            if batch_use.read().iter().all(|b| b.used.load(Ordering::Relaxed) != 0) {
+                return 0;
+            }
+        }
+        self.release_empty(None) * self.layout.size()
+    }
+
+    /// Empties the shared free list, including the slots kept by the current thread, returning how
+    /// many slots were removed. The memory is not released: the slots are lost to the `Pool` until
+    /// `rebuild_free_list`, and freed when the `Pool` is dropped.
+    ///
+    /// Slots returned by other threads later go to the free list as usual.
+    pub fn clear_free_list(&self) -> usize {
+        self.spill_local();
+        let mut count = 0;
+        while self.free.try_pop().is_some() {
+            count += 1;
+        }
+        let mut spilled = self.spilled.lock();
+        count += spilled.len();
+        spilled.clear();
+        count
+    }
+
+    /// Rebuilds the free list from the liveness bitmap: every slot of every batch that is not used
+    /// by an `Object` becomes free, once. A recovery tool, and the counterpart of
+    /// `clear_free_list`. Takes `O(capacity)` time.
+    ///
+    /// The slots kept by other threads for their own allocations are forgotten by them, so they
+    /// can't be handed out twice. For the same reason, slots detached before can't be returned to
+    /// the `Pool` afterwards: `return_slots` panics as if they came from a different `Pool`, and
+    /// `WeakObject`s made before never see their values alive again.
+    ///
+    /// Panics if the `Pool` was not created with `track_liveness`.
+    pub fn rebuild_free_list(&mut self) {
+        assert!(self.liveness.is_some(), "Pool::rebuild_free_list requires a Pool created with track_liveness");
+        self.spill_local();
+        // a new identity disowns the slots kept by threads and the detached slots
+        self.id = NEXT_POOL_ID.fetch_add(1, Ordering::Relaxed);
+        self.spilled = Arc::new(Mutex::new(Vec::new()));
+        while self.free.try_pop().is_some() {}
+        let liveness = self.liveness.as_ref().unwrap().read();
+        for block in liveness.iter() {
+            for i in 0..self.batch {
+                let used = block.bits[i / USIZE_BITS].load(Ordering::Relaxed) & (1 << (i % USIZE_BITS)) != 0;
+                if !used {
+                    self.free.push(block.start + i * self.stride);
+                }
+            }
+        }
+    }
+
+    /// Releases the batches whose slots are all free, or only the one starting at `only`. Returns
+    /// the number of batches released.
+    fn release_empty(&self, only: Option<usize>) -> usize {
+        self.spill_local();
+        let mut lock = self.data.lock();
+        let mut free = Vec::new();
+        while let Some(x) = self.free.try_pop() {
+            free.push(x);
+        }
+        free.append(&mut self.spilled.lock());
+        // (block start, free slots in block), sorted by address
+        let mut blocks: Vec<(usize, usize)> = lock.iter().map(|b| (*b as usize, 0)).collect();
+        blocks.sort();
+        for x in &free {
+            let index = block_index(&blocks, *x);
+            blocks[index].1 += 1;
+        }
+        // the batches that are not to be released are counted as used
+        if let Some(only) = only {
+            for b in &mut blocks {
+                if b.0 != only {
+                    b.1 = 0;
+                }
+            }
+        }
+        for x in free {
+            if blocks[block_index(&blocks, x)].1 != self.batch {
+                self.free.push(x);
+            }
+        }
+        let batch = self.batch;
+        lock.retain(|b| {
+            let index = block_index(&blocks, *b as usize);
+            blocks[index].1 != batch
+        });
+        if let Some(ref liveness) = self.liveness {
+            liveness.write().retain(|b| blocks[block_index(&blocks, b.start)].1 != batch);
+        }
+        if let Some(ref batch_use) = self.batch_use {
+            batch_use.write().retain(|b| blocks[block_index(&blocks, b.start)].1 != batch);
+        }
+        if let Some(ref generations) = self.generations {
+            generations.write().retain(|b| blocks[block_index(&blocks, b.start)].1 != batch);
+        }
+        let mut released = 0;
+        for &(block, count) in &blocks {
+            if count == batch {
+                unsafe {
+                    self.allocator.dealloc(block as *mut u8, self.layout);
+                }
+                self.capacity.fetch_sub(batch, Ordering::Relaxed);
+                released += 1;
+            }
+        }
+        released
+    }
+
+    /// The total number of slots allocated by the `Pool`, both used and free.
+    /// For zero-sized types it is `usize::MAX`, since they never allocate.
+    ///
+    /// Lock-free. Under concurrent usage the value might be outdated by the time it is used.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        if mem::size_of::<T>() == 0 {
+            usize::MAX
+        } else {
+            self.capacity.load(Ordering::Relaxed)
+        }
+    }
+
+    /// The number of `Object`s currently alive that were allocated from this `Pool`.
+    ///
+    /// Lock-free. Under concurrent usage the value might be outdated by the time it is used.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.live.load(Ordering::Relaxed)
+    }
+
+    /// The number of slots that can be taken without allocating a new batch: `capacity() - len()`,
+    /// not counting the values allocated on their own by a `Pool` created with `overflow`. For
+    /// zero-sized types it is `usize::MAX`, since they never allocate.
+    ///
+    /// Lock-free. It is an approximation: the counters are updated separately, so under concurrent
+    /// usage the value might be off by the objects being pushed or dropped. Detached slots and the
+    /// slots kept by other threads for their own allocations are counted as free.
+    #[inline]
+    pub fn free_slots(&self) -> usize {
+        if mem::size_of::<T>() == 0 {
+            return usize::MAX;
+        }
+        let overflow = self.overflow.as_ref().map_or(0, |o| o.count.load(Ordering::Relaxed));
+        self.capacity().saturating_sub(self.len().saturating_sub(overflow))
+    }
+
+    /// Returns `true` if there are no live `Object`s allocated from this `Pool`.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns `true` if the slot of `o` belongs to one of the batches of this `Pool`. Meant for
+    /// debugging mix-ups between pools of the same type.
+    ///
+    /// Takes the lock and checks every batch, so it is `O(batches)`. Zero-sized types have no batches,
+    /// so for them the `Pool` that allocated `o` is compared instead.
+    pub fn contains(&self, o: &Object<'_, T, A>) -> bool {
+        if mem::size_of::<T>() == 0 {
+            return ptr::eq(o.manager, self);
+        }
+        if self.is_overflow(o.obj) {
+            return true;
+        }
+        let ptr = o.obj as usize;
+        let size = self.batch * self.stride;
+        self.data
+            .lock()
+            .iter()
+            .any(|b| {
+                let start = *b as usize;
+                start <= ptr && ptr < start + size && (ptr - start).is_multiple_of(self.stride)
+            })
+    }
+
+    /// Gathers the memory statistics of the `Pool`. Takes the lock.
+    pub fn stats(&self) -> PoolStats {
+        let blocks = self.data.lock().len();
+        let total_slots = blocks * self.batch;
+        let live = self.len();
+        let overflow = self.overflow.as_ref().map_or(0, |o| o.count.load(Ordering::Relaxed));
+        PoolStats {
+            blocks,
+            batch: self.batch,
+            stride: self.stride,
+            bytes_allocated: blocks * self.layout.size(),
+            total_slots,
+            live,
+            free: total_slots.saturating_sub(live.saturating_sub(overflow)),
+        }
+    }
+
+    /// The number of objects allocated together in a batch.
+    #[inline]
+    pub fn batch_size(&self) -> usize {
+        self.batch
+    }
+
+    /// The distance in bytes between adjacent objects in a batch.
+    #[inline]
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// The start of every batch, in the order they were allocated. The slots of a batch start at
+    /// `base`, and are `stride()` bytes apart: slot `i` is at `base + i * stride()`, for `i` lower
+    /// than `batch_size()`. Meant for tracking the slots outside of the `Pool`, like a custom
+    /// garbage collector. Values allocated on their own by an overflowing `Pool` are not in any
+    /// batch.
+    ///
+    /// Takes the lock. The pointers are only a snapshot: other threads can take and return slots
+    /// meanwhile, and `shrink_to_fit` or an `EmptyBatchPolicy` can release batches after the call.
+    /// Reading through them is only sound if the caller knows the slot holds a live value that is
+    /// not mutated meanwhile.
+    pub fn batch_bases(&self) -> Vec<*const T> {
+        self.data.lock().iter().map(|b| *b as *const T).collect()
+    }
+
+    /// The size in bytes of the memory allocated for each batch.
+    #[inline]
+    pub fn block_size(&self) -> usize {
+        self.layout.size()
+    }
+
+    /// The alignment every slot is guaranteed to have.
+    #[inline]
+    pub fn slot_alignment(&self) -> usize {
+        if self.stride == 0 {
+            // zero-sized types don't have memory, their slots are only aligned for `T`
+            mem::align_of::<T>()
+        } else {
+            self.layout.align().min(1 << self.stride.trailing_zeros())
+        }
+    }
+
+    /// Panics unless a value of type `U` fits in the slots of this `Pool`: it must not be larger
+    /// than `stride()`, and must not need a larger alignment than `slot_alignment()`.
+    ///
+    /// Meant to be called before unsafe code reuses the slots for a different type, to check the
+    /// invariants that code relies on. It doesn't make the reuse safe by itself.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use veryfast::pool::Pool;
+    ///
+    /// let pool = Pool::<u64>::new();
+    /// pool.assert_compatible::<[u8; 8]>();
+    /// pool.assert_compatible::<f64>();
+    /// let obj = pool.push(0u64);
+    /// // a slot of the `Pool` holds an `f64` just as well
+    /// let as_f64 = unsafe { &*(&*obj as *const u64 as *const f64) };
+    /// assert_eq!(*as_f64, 0.0);
+    /// ```
+    ///
+    /// ```should_panic
+    /// use veryfast::pool::Pool;
+    ///
+    /// // 12 byte slots are only 4 byte aligned
+    /// Pool::<[u32; 3]>::new().assert_compatible::<u64>();
+    /// ```
+    pub fn assert_compatible<U>(&self) {
+        assert!(mem::size_of::<U>() <= self.stride,
+                "{} of {} bytes doesn't fit in the {} byte slots of the Pool",
+                core::any::type_name::<U>(),
+                mem::size_of::<U>(),
+                self.stride);
+        assert!(mem::align_of::<U>() <= self.slot_alignment(),
+                "{} needs an alignment of {}, but the slots of the Pool are aligned to {}",
+                core::any::type_name::<U>(),
+                mem::align_of::<U>(),
+                self.slot_alignment());
+    }
+
+    /// Returns slots detached by `Object::recover_detached` to the `Pool`, making them available for
+    /// new allocations.
+    ///
+    /// The slots are handed over together under a single lock, instead of going through the shared
+    /// free list one by one. They are moved to the free list by the first allocation that finds it
+    /// empty.
+    ///
+    /// Panics if a slot was detached from a different `Pool`.
+    pub fn return_slots<I>(&self, slots: I)
+        where I: IntoIterator<Item = DetachedSlot<T>>
+    {
+        let slots: Vec<usize> = slots.into_iter()
+            .map(|slot| {
+                assert!(slot.pool == self.id, "DetachedSlot returned to a different Pool");
+                slot.slot as usize
+            })
+            // the slots allocated on their own were freed already
+            .filter(|slot| *slot != 0)
+            .collect();
+        if let Some(ref hook) = self.return_hook {
+            for slot in &slots {
+                hook(*slot as *mut ());
+            }
+        }
+        if mem::size_of::<T>() != 0 {
+            // counted before the slots are published, when no other thread can release their batches
+            let empty: Vec<usize> = slots.iter().filter_map(|slot| self.count_use(*slot as *mut T, false)).collect();
+            self.spilled.lock().extend(slots);
+            for block in empty {
+                self.empty_batch(block);
+            }
+        }
+    }
+
+    /// Moves the values out of `objects` into a `Vec`, in the same order, and returns all their slots
+    /// to the `Pool` together, like `Object::recover_detached` followed by `return_slots`.
+    ///
+    /// Panics if an `Object` belongs to a different `Pool`. The slots detached before the panic are
+    /// not returned, their memory is freed with the `Pool`.
+    pub fn recover_all<'a, I>(&'a self, objects: I) -> Vec<T>
+        where I: IntoIterator<Item = Object<'a, T, A>>
+    {
+        let objects = objects.into_iter();
+        let mut values = Vec::with_capacity(objects.size_hint().0);
+        let mut slots = Vec::with_capacity(objects.size_hint().0);
+        for o in objects {
+            assert!(ptr::eq(o.manager, self), "Object recovered into a different Pool");
+            let (value, slot) = Object::recover_detached(o);
+            values.push(value);
+            slots.push(slot);
+        }
+        self.return_slots(slots);
+        values
+    }
+
+    /// Takes a free slot, preferring the ones kept by the current thread.
+    #[inline]
+    fn pop_free(&self) -> Option<*mut T> {
+        self.pop_local().or_else(|| self.free.try_pop()).map(|x| x as *mut T)
+    }
+
+    #[inline]
+    fn ret_ptr(&self, obj: *mut T) {
+        self.live.fetch_sub(1, Ordering::Relaxed);
+        let mut empty = None;
+        if self.is_overflow(obj) {
+            self.free_overflow(obj);
+        } else if mem::size_of::<T>() != 0 {
+            self.mark_live(obj, false);
+            self.generation(obj, true);
+            // counted before the slot is published, when no other thread can release its batch
+            empty = self.count_use(obj, false);
+            if !self.push_local(obj as usize) {
+                self.free.push(obj as usize);
+            }
+        }
+        if let Some(ref hook) = self.return_hook {
+            hook(obj as *mut ());
+        }
+        if let Some(block) = empty {
+            self.empty_batch(block);
+        }
+    }
+
+    /// Takes a slot kept by the current thread.
+    #[cfg(feature = "std")]
+    #[inline]
+    fn pop_local(&self) -> Option<usize> {
+        LOCAL_CACHES
+            .try_with(|caches| {
+                let mut caches = caches.borrow_mut();
+                caches.iter_mut().find(|c| c.pool == self.id).and_then(|c| c.slots.pop())
+            })
+            .unwrap_or(None)
+    }
+
+    /// Without `std` there is no thread-local storage, so threads don't keep slots.
+    #[cfg(not(feature = "std"))]
+    #[inline]
+    fn pop_local(&self) -> Option<usize> {
+        None
+    }
+
+    /// Keeps a freed slot for the current thread, if it doesn't keep too many already.
+    #[cfg(feature = "std")]
+    #[inline]
+    fn push_local(&self, slot: usize) -> bool {
+        LOCAL_CACHES
+            .try_with(|caches| {
+                let mut caches = caches.borrow_mut();
+                let index = match caches.iter().position(|c| c.pool == self.id) {
+                    Some(x) => x,
+                    None => {
+                        // forget about the pools that were dropped
+                        caches.retain(|c| c.spilled.strong_count() != 0);
+                        caches.push(LocalCache {
+                            pool: self.id,
+                            spilled: Arc::downgrade(&self.spilled),
+                            slots: Vec::with_capacity(LOCAL_CACHE_SIZE),
+                        });
+                        caches.len() - 1
+                    }
+                };
+                let slots = &mut caches[index].slots;
+                if slots.len() < LOCAL_CACHE_SIZE {
+                    slots.push(slot);
+                    true
+                } else {
+                    false
+                }
+            })
+            .unwrap_or(false)
+    }
+
+    #[cfg(not(feature = "std"))]
+    #[inline]
+    fn push_local(&self, _slot: usize) -> bool {
+        false
+    }
+
+    /// Returns the slots kept by the current thread to the `Pool`.
+    #[cfg(not(feature = "std"))]
+    fn spill_local(&self) {}
+
+    /// Returns the slots kept by the current thread to the `Pool`.
+    #[cfg(feature = "std")]
+    fn spill_local(&self) {
+        let _ = LOCAL_CACHES.try_with(|caches| {
+            let mut caches = caches.borrow_mut();
+            if let Some(index) = caches.iter().position(|c| c.pool == self.id) {
+                caches.swap_remove(index);
+            }
+        });
+    }
+}
+
+/// Memory statistics of a `Pool`, returned by `Pool::stats`.
+///
+/// Zero-sized types never allocate, so their `Pool` has no blocks and no slots.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// The number of batches allocated.
+    pub blocks: usize,
+    /// The number of slots in each batch.
+    pub batch: usize,
+    /// The distance in bytes between adjacent slots.
+    pub stride: usize,
+    /// The total size in bytes of the allocated batches.
+    pub bytes_allocated: usize,
+    /// The number of slots in all the batches, used and free.
+    pub total_slots: usize,
+    /// The number of live `Object`s.
+    pub live: usize,
+    /// The number of slots available for new objects.
+    pub free: usize,
+}
+
+/// A slot of a `Pool` that has no value in it, created by `Object::recover_detached`.
+///
+/// It can be returned to its `Pool` with `Pool::return_slots`. If it is dropped instead, the slot is
+/// lost to the `Pool` like with `Object::leak`.
+#[must_use = "the slot is lost to the Pool unless it is returned with Pool::return_slots"]
+pub struct DetachedSlot<T> {
+    slot: *mut T,
+    pool: usize,
+}
+
+unsafe impl<T> Send for DetachedSlot<T> {}
+
+impl<T> fmt::Debug for DetachedSlot<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DetachedSlot {{ {:p} }}", self.slot)
+    }
+}
+
+/// Returns a taken slot to its `Pool` when dropped, so the slot isn't lost if a panic happens before
+/// the slot is owned by an `Object`, or while the value of an `Object` is dropped.
+struct SlotGuard<'a, T: 'a, A: 'a + GlobalAlloc> {
+    slot: *mut T,
+    pool: &'a Pool<T, A>,
+}
+
+impl<'a, T, A: GlobalAlloc> Drop for SlotGuard<'a, T, A> {
+    #[inline]
+    fn drop(&mut self) {
+        self.pool.ret_ptr(self.slot);
+    }
+}
+
+/// The liveness bitmap of a batch, one bit per slot.
+struct LiveBlock {
+    start: usize,
+    bits: Box<[AtomicUsize]>,
+}
+
+/// The generations of the slots of a batch, used by `WeakObject` and in debug builds to detect
+/// `Object`s that outlived their slot.
+struct GenerationBlock {
+    start: usize,
+    generations: Box<[AtomicUsize]>,
+}
+
+/// The values a `Pool` created with `overflow` allocated on their own.
+struct Overflow {
+    layout: Layout,
+    // the address and generation of every value, sorted by address
+    slots: Mutex<Vec<(usize, usize)>>,
+    count: AtomicUsize,
+}
+
+/// The number of used slots of a batch, kept when the `Pool` releases empty batches.
+struct BatchUse {
+    start: usize,
+    used: AtomicUsize,
+}
+
+const USIZE_BITS: usize = mem::size_of::<usize>() * 8;
+
+/// How many free slots each thread keeps for its own allocations before returning them to the
+/// shared free list.
+#[cfg(feature = "std")]
+const LOCAL_CACHE_SIZE: usize = 8;
+
+/// Gives every `Pool` a unique id, so the slots kept by a thread are never mixed between pools.
+static NEXT_POOL_ID: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(feature = "std")]
+thread_local! {
+    static LOCAL_CACHES: RefCell<Vec<LocalCache>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The free slots of one `Pool` kept by a thread.
+#[cfg(feature = "std")]
+struct LocalCache {
+    pool: usize,
+    spilled: Weak<Mutex<Vec<usize>>>,
+    slots: Vec<usize>,
+}
+
+#[cfg(feature = "std")]
+impl Drop for LocalCache {
+    /// Returns the slots to the `Pool`, on thread exit or when the `Pool` asks for them.
+    /// If the `Pool` was already dropped, its memory is gone and the slots are simply forgotten.
+    ///
+    /// The slots can't go directly to the free list, since it uses thread-local storage that might
+    /// be already destroyed on thread exit. They are picked up from `spilled` by the next allocation
+    /// that takes the lock.
+    fn drop(&mut self) {
+        if let Some(spilled) = self.spilled.upgrade() {
+            let mut spilled = spilled.lock();
+            spilled.append(&mut self.slots);
+        }
+    }
+}
+
+/// The size in bytes of a CPU cache line on the running system, as used by `Pool::with_params`.
+///
+/// On Linux with the `std` feature it is read from sysfs the first time it's needed. Otherwise, or if that fails, it is
+/// a constant for the target architecture:
+///
+/// - 128 on Apple aarch64 and on powerpc64
+/// - 256 on s390x
+/// - 64 everywhere else, including x86 and x86_64
+pub fn cache_line_size() -> usize {
+    static DETECTED: AtomicUsize = AtomicUsize::new(0);
+    let size = DETECTED.load(Ordering::Relaxed);
+    if size != 0 {
+        return size;
+    }
+    let size = match probe_cache_line_size() {
+        Some(x) if x.is_power_of_two() => x,
+        _ => DEFAULT_CACHE_LINE_SIZE,
+    };
+    DETECTED.store(size, Ordering::Relaxed);
+    size
+}
+
+#[cfg(all(target_arch = "aarch64", target_vendor = "apple"))]
+const DEFAULT_CACHE_LINE_SIZE: usize = 128;
+#[cfg(target_arch = "powerpc64")]
+const DEFAULT_CACHE_LINE_SIZE: usize = 128;
+#[cfg(target_arch = "s390x")]
+const DEFAULT_CACHE_LINE_SIZE: usize = 256;
+#[cfg(not(any(all(target_arch = "aarch64", target_vendor = "apple"),
+              target_arch = "powerpc64",
+              target_arch = "s390x")))]
+const DEFAULT_CACHE_LINE_SIZE: usize = 64;
+
+#[cfg(all(feature = "std", target_os = "linux"))]
+fn probe_cache_line_size() -> Option<usize> {
+    let size = ::std::fs::read_to_string("/sys/devices/system/cpu/cpu0/cache/index0/coherency_line_size").ok()?;
+    size.trim().parse().ok()
+}
+
+#[cfg(not(all(feature = "std", target_os = "linux")))]
+fn probe_cache_line_size() -> Option<usize> {
+    None
+}
+
+/// The size of a memory page.
+#[cfg(all(feature = "numa", target_os = "linux"))]
+fn page_size() -> usize {
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        x if x > 0 => x as usize,
+        _ => 4096,
+    }
+}
+
+/// Asks the kernel to place the memory of a new batch on the NUMA node `node`, with `mbind`. It is
+/// a hint: the memory comes from other nodes when `node` runs out, and errors are ignored. Only the
+/// pages entirely inside the batch are bound, so the memory of other allocations is not affected.
+#[cfg(all(feature = "numa", target_os = "linux"))]
+fn bind_to_node(block: *mut u8, size: usize, node: u32) {
+    const MPOL_PREFERRED: libc::c_ulong = 1;
+    const MPOL_MF_MOVE: libc::c_ulong = 1 << 1;
+    let page = page_size();
+    let start = (block as usize).next_multiple_of(page);
+    let end = (block as usize + size) / page * page;
+    if start >= end {
+        return;
+    }
+    let bits = mem::size_of::<libc::c_ulong>() * 8;
+    let node = node as usize;
+    let mask: Vec<libc::c_ulong> = (0..node / bits + 1)
+        .map(|i| if i == node / bits { 1 << (node % bits) } else { 0 })
+        .collect();
+    unsafe {
+        // the kernel reads one bit less than `maxnode`
+        libc::syscall(libc::SYS_mbind,
+                      start as libc::c_ulong,
+                      (end - start) as libc::c_ulong,
+                      MPOL_PREFERRED,
+                      mask.as_ptr(),
+                      (mask.len() * bits + 1) as libc::c_ulong,
+                      MPOL_MF_MOVE);
+    }
+}
+
+/// NUMA placement is only supported on Linux.
+#[cfg(all(feature = "numa", not(target_os = "linux")))]
+fn bind_to_node(_block: *mut u8, _size: usize, _node: u32) {}
+
+/// Finds the block containing `ptr` in a list of blocks sorted by their start address.
+#[inline]
+fn block_index(blocks: &[(usize, usize)], ptr: usize) -> usize {
+    match blocks.binary_search_by_key(&ptr, |b| b.0) {
+        Ok(i) => i,
+        Err(i) => i - 1,
+    }
+}
+
+impl<T> Default for Pool<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, A: GlobalAlloc> Drop for Pool<T, A> {
+    #[inline]
+    fn drop(&mut self) {
+        let lock = self.data.lock();
+        for block in lock.deref() {
+            unsafe {
+                self.allocator.dealloc(*block as *mut u8, self.layout);
+            }
+        }
+        if let Some(ref overflow) = self.overflow {
+            for &(slot, _) in overflow.slots.lock().iter() {
+                unsafe {
+                    self.allocator.dealloc(slot as *mut u8, overflow.layout);
+                }
+            }
+        }
+    }
+}
+
+impl<'active, T, A: GlobalAlloc> Object<'active, T, A> {
+    /// Creates the `Object` owning the value in `obj`, a slot of `manager`.
+    #[inline]
+    fn from_slot(obj: *mut T, manager: &'active Pool<T, A>) -> Self {
+        Object {
+            obj,
+            manager,
+            #[cfg(debug_assertions)]
+            generation: manager.generation(obj, false),
+        }
+    }
+
+    /// Panics if the slot was released since the `Object` was created, which means the `Object`
+    /// was duplicated by unsafe code and the value it points to isn't its own anymore.
+    #[cfg(debug_assertions)]
+    #[inline]
+    fn check_generation(&self) {
+        assert!(self.manager.generation(self.obj, false) == self.generation,
+                "Object used after its slot was released");
+    }
+
+    /// Returns the owned object from the pool-allocated memory.
+    #[allow(clippy::needless_pass_by_value)]
+    #[inline]
+    pub fn recover(t: Self) -> T {
+        let ret = unsafe {
+            ptr::read(t.obj)
+        };
+        t.manager.ret_ptr(t.obj);
+        mem::forget(t);
+        ret
+    }
+
+    /// Moves the owned object into a new `Box`, returning the slot to the `Pool`.
+    #[allow(clippy::needless_pass_by_value)]
+    #[inline]
+    pub fn into_box(o: Self) -> Box<T> {
+        Box::new(Object::recover(o))
+    }
+
+    /// Returns the owned object from the pool-allocated memory, like `recover`, but doesn't return the
+    /// slot to the `Pool` yet.
+    ///
+    /// The slot can be returned later together with others by `Pool::return_slots`, to avoid the
+    /// overhead of returning the slots one by one when many objects are recovered.
+    #[inline]
+    pub fn recover_detached(t: Self) -> (T, DetachedSlot<T>) {
+        let ret = unsafe {
+            ptr::read(t.obj)
+        };
+        let mut slot = DetachedSlot {
+            slot: t.obj,
+            pool: t.manager.id,
+        };
+        if t.manager.is_overflow(t.obj) {
+            // a value allocated on its own has no slot to keep, its memory is freed right away
+            t.manager.ret_ptr(t.obj);
+            slot.slot = ptr::null_mut();
+        } else {
+            t.manager.live.fetch_sub(1, Ordering::Relaxed);
+            t.manager.mark_live(t.obj, false);
+            t.manager.generation(t.obj, true);
+        }
+        mem::forget(t);
+        (ret, slot)
+    }
+
+    /// Replaces the owned value with `new`, returning the old one, like `mem::replace`.
+    ///
+    /// The slot is kept: the new value is written in the same place, without returning the slot to
+    /// the `Pool` and taking another one.
+    #[inline]
+    pub fn replace(o: &mut Self, new: T) -> T {
+        mem::replace(&mut **o, new)
+    }
+
+    /// Returns `true` if both `Object`s own the same slot, like `Rc::ptr_eq`.
+    #[inline]
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        a.obj == b.obj
+    }
+
+    /// Returns a pinned mutable reference to the value.
+    ///
+    /// A value never moves while it is in its slot: batches are never reallocated, and a slot is
+    /// only reused after its value was dropped or moved out.
+    ///
+    /// # Safety
+    ///
+    /// The `Object` itself doesn't keep the value pinned, so for types that are not `Unpin`:
+    ///
+    /// - The value must not be moved out of the `Object` afterwards, by `recover`, `into_box` or
+    ///   through `DerefMut`.
+    /// - The `Object` must be dropped, not forgotten or leaked. Dropping the `Pool` frees the memory
+    ///   of forgotten objects without dropping their values.
+    ///
+    /// `OwnedObject::into_pin` has no such requirements.
+    #[inline]
+    pub unsafe fn as_pin_mut(o: &mut Self) -> Pin<&mut T> {
+        Pin::new_unchecked(&mut **o)
+    }
+
+    /// Consumes the `Object` without dropping the value, returning a pointer to it, like
+    /// `Box::into_raw`. Meant for passing the value through FFI.
+    ///
+    /// The value keeps its slot. `Pool::from_raw` creates an `Object` owning it again, which
+    /// drops the value and returns the slot as usual. Until then the value is leaked, like with
+    /// `leak`.
+    #[inline]
+    pub fn into_raw(o: Self) -> *mut T {
+        let obj = o.obj;
+        mem::forget(o);
+        obj
+    }
+
+    /// Consumes the `Object` without dropping the value, returning a reference to it that lives as
+    /// long as the `Pool`.
+    ///
+    /// The slot is permanently lost to the `Pool`: it will never be reused, so the effective capacity
+    /// drops by one. It is still counted by `len`, and its batch can't be released by `shrink_to_fit`.
+    /// The value's destructor will never run.
+    #[inline]
+    pub fn leak(o: Self) -> &'active mut T {
+        let obj = o.obj;
+        mem::forget(o);
+        unsafe { &mut *obj }
+    }
+
+    /// Makes a new `MappedObject` for a component of the owned value, like `RefMut::map`.
+    ///
+    /// The whole value is still owned by the returned `MappedObject`, and will be dropped with it.
+    #[inline]
+    pub fn map<U, F>(mut o: Self, f: F) -> MappedObject<'active, T, U, A>
+        where F: FnOnce(&mut T) -> &mut U
+    {
+        let value = f(&mut *o) as *mut U;
+        MappedObject {
+            object: o,
+            value,
+        }
+    }
+
+    /// Reinterprets the value as a `U` in place, like `mem::transmute`, without copying it.
+    ///
+    /// The returned `Object` owns the same slot, and drops it as a `U`. It refers to the same
+    /// `Pool`, reinterpreted as a `Pool<U>`: a `Pool` doesn't store anything of type `T` and has the
+    /// same layout for every `T`, and finds its slots by their addresses only, so the slot is
+    /// returned to the right batch.
+    ///
+    /// # Safety
+    ///
+    /// - The value must be a valid `U`, like for `mem::transmute`.
+    /// - `U` must have the same size as `T`, and must not need a larger alignment than
+    ///   `Pool::slot_alignment`. Checked in debug builds.
+    /// - The methods of the `Pool` that visit the values, like `for_each_live`, must not be called
+    ///   while the returned `Object` is alive, as they would see a `U` as a `T`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use veryfast::pool::{Object, Pool};
+    ///
+    /// // a packet header received as bytes, in native byte order
+    /// let pool = Pool::<[u8; 4]>::new();
+    /// let header = pool.push(0x0102_0304u32.to_ne_bytes());
+    /// // the bytes are a valid `u32`, and the slots of the `Pool` are aligned for one
+    /// pool.assert_compatible::<u32>();
+    /// let header: Object<u32> = unsafe { Object::cast(header) };
+    /// assert_eq!(*header, 0x0102_0304);
+    /// ```
+    #[inline]
+    pub unsafe fn cast<U>(o: Self) -> Object<'active, U, A> {
+        debug_assert!(mem::size_of::<U>() == mem::size_of::<T>() && mem::align_of::<U>() <= o.manager.slot_alignment(),
+                      "Object cast to a type of a different size or a larger alignment");
+        let cast = Object {
+            obj: o.obj as *mut U,
+            manager: &*(o.manager as *const Pool<T, A> as *const Pool<U, A>),
+            #[cfg(debug_assertions)]
+            generation: o.generation,
+        };
+        mem::forget(o);
+        cast
+    }
+
+    /// Returns a shared reference to the value.
+    ///
+    /// The same as going through `Deref`, but usable where a `&Object` has to be passed on as a
+    /// plain `&T`, for example to threads that must only read the value.
+    #[inline]
+    pub fn as_shared(o: &Self) -> &T {
+        o
+    }
+
+    /// Creates a `WeakObject` for the value, which can tell whether the value is still alive without
+    /// keeping it alive.
+    ///
+    /// Panics if the `Pool` was not created with `track_generations`. Debug builds always track
+    /// generations.
+    #[inline]
+    pub fn downgrade(o: &Self) -> WeakObject<T, A> {
+        assert!(o.manager.generations.is_some(), "Object::downgrade requires a Pool created with track_generations");
+        WeakObject {
+            obj: o.obj as usize,
+            generation: o.manager.generation(o.obj, false),
+            pool: o.manager.id,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Turns the `Object` into a `SharedObject`, which can be cloned to give several threads
+    /// shared access to the value.
+    ///
+    /// The reference count is kept in a small heap allocation, outside of the `Pool`.
+    #[inline]
+    pub fn into_shared(o: Self) -> SharedObject<'active, T, A> {
+        SharedObject {
+            object: Arc::new(o),
+        }
+    }
+}
+
+impl<'active, T, U, A: GlobalAlloc> Deref for MappedObject<'active, T, U, A> {
+    type Target = U;
+
+    #[allow(clippy::inline_always)]
+    #[inline(always)]
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.value }
+    }
+}
+
+impl<'active, T, U, A: GlobalAlloc> DerefMut for MappedObject<'active, T, U, A> {
+    #[allow(clippy::inline_always)]
+    #[inline(always)]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.value }
+    }
+}
+
+impl<T, A: GlobalAlloc> WeakObject<T, A> {
+    /// Returns `true` if the value the handle was made for is still in `pool`.
+    #[inline]
+    pub fn is_alive(&self, pool: &Pool<T, A>) -> bool {
+        pool.id == self.pool && pool.generation(self.obj as *mut T, false) == self.generation
+    }
+
+    /// Returns a reference to the value if it is still in `pool`, like `rc::Weak::upgrade`.
+    ///
+    /// # Safety
+    ///
+    /// The handle doesn't keep the value alive, so the `Object` owning it must not drop it, move it
+    /// out or mutate it during this call or while the returned reference is used. For example,
+    /// the owner and the observers only run one at a time.
+    #[inline]
+    pub unsafe fn upgrade<'a>(&self, pool: &'a Pool<T, A>) -> Option<&'a T> {
+        if self.is_alive(pool) {
+            Some(&*(self.obj as *const T))
+        } else {
+            None
+        }
+    }
+}
+
+impl<T, A: GlobalAlloc> Clone for WeakObject<T, A> {
+    #[inline]
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T, A: GlobalAlloc> Copy for WeakObject<T, A> {}
+
+impl<'active, T, A: GlobalAlloc> SharedObject<'active, T, A> {
+    /// Returns the `Object` if this is the only clone left, or the `SharedObject` otherwise, like
+    /// `Arc::try_unwrap`.
+    #[inline]
+    pub fn try_unwrap(s: Self) -> Result<Object<'active, T, A>, Self> {
+        Arc::try_unwrap(s.object).map_err(|object| SharedObject { object })
+    }
+
+    /// Returns `true` if both `SharedObject`s share the same value, like `Arc::ptr_eq`.
+    #[inline]
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        Arc::ptr_eq(&a.object, &b.object)
+    }
+
+    /// Returns the number of clones sharing the value.
+    #[inline]
+    pub fn strong_count(s: &Self) -> usize {
+        Arc::strong_count(&s.object)
+    }
+}
+
+impl<'active, T, A: GlobalAlloc> Clone for SharedObject<'active, T, A> {
+    #[inline]
+    fn clone(&self) -> Self {
+        SharedObject {
+            object: self.object.clone(),
+        }
+    }
+}
+
+impl<'active, T, A: GlobalAlloc> Deref for SharedObject<'active, T, A> {
+    type Target = T;
+
+    #[allow(clippy::inline_always)]
+    #[inline(always)]
+    fn deref(&self) -> &Self::Target {
+        &self.object
+    }
+}
+
+impl<'active, T, A: GlobalAlloc> fmt::Debug for SharedObject<'active, T, A>
+    where T: fmt::Debug
+{
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<O> ByAddress<O> {
+    /// Returns the wrapped `Object` or reference.
+    #[inline]
+    pub fn into_inner(self) -> O {
+        self.0
+    }
+}
+
+impl<O> Deref for ByAddress<O> {
+    type Target = O;
+
+    #[inline]
+    fn deref(&self) -> &O {
+        &self.0
+    }
+}
+
+impl<O> DerefMut for ByAddress<O> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut O {
+        &mut self.0
+    }
+}
+
+impl<'active, T, A: GlobalAlloc> PartialEq for ByAddress<Object<'active, T, A>> {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.0.obj == other.0.obj
+    }
+}
+
+impl<'active, T, A: GlobalAlloc> Eq for ByAddress<Object<'active, T, A>> {}
+
+impl<'active, T, A: GlobalAlloc> Hash for ByAddress<Object<'active, T, A>> {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.obj.hash(state)
+    }
+}
+
+impl<'active, T, A: GlobalAlloc> PartialEq for ByAddress<&Object<'active, T, A>> {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        ptr::eq(self.0, other.0)
+    }
+}
+
+impl<'active, T, A: GlobalAlloc> Eq for ByAddress<&Object<'active, T, A>> {}
+
+impl<'active, T, A: GlobalAlloc> Hash for ByAddress<&Object<'active, T, A>> {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.0 as *const Object<'active, T, A>).hash(state)
+    }
+}
+
+impl<'active, T, A: GlobalAlloc> Drop for Object<'active, T, A> {
+    #[inline]
+    fn drop(&mut self) {
+        // returns the slot even if the destructor panics
+        let _guard = SlotGuard {
+            slot: self.obj,
+            pool: self.manager,
+        };
+        unsafe {
+            ptr::drop_in_place(self.obj);
+        }
+    }
+}
+
+impl<'active, T, A: GlobalAlloc> Deref for Object<'active, T, A> {
+    type Target = T;
+
+    #[allow(clippy::inline_always)]
+    #[inline(always)]
+    fn deref(&self) -> &Self::Target {
+        #[cfg(debug_assertions)]
+        self.check_generation();
+        unsafe { &*self.obj }
+    }
+}
+
+impl<'active, T, A: GlobalAlloc> DerefMut for Object<'active, T, A> {
+    #[allow(clippy::inline_always)]
+    #[inline(always)]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        #[cfg(debug_assertions)]
+        self.check_generation();
+        unsafe { &mut *self.obj }
+    }
+}
+
+impl<'active, T, A: GlobalAlloc> AsRef<T> for Object<'active, T, A> {
+    #[inline]
+    fn as_ref(&self) -> &T {
+        self
+    }
+}
+
+impl<'active, T, A: GlobalAlloc> AsMut<T> for Object<'active, T, A> {
+    #[inline]
+    fn as_mut(&mut self) -> &mut T {
+        self
+    }
+}
+
+impl<'active, T, A: GlobalAlloc> Borrow<T> for Object<'active, T, A> {
+    #[inline]
+    fn borrow(&self) -> &T {
+        self
+    }
+}
+
+impl<'active, T, A: GlobalAlloc> BorrowMut<T> for Object<'active, T, A> {
+    #[inline]
+    fn borrow_mut(&mut self) -> &mut T {
+        self
+    }
+}
+
+impl<T: ?Sized> DynPool<T> {
+    /// Creates a new `DynPool`.
+    #[inline]
+    pub fn new() -> DynPool<T> {
+        DynPool::from(Pool::new())
+    }
+}
+
+impl<T: ?Sized, A: GlobalAlloc> DynPool<T, A> {
+    /// Stores the pointer of a boxed value in the `Pool`.
+    ///
+    /// Will panic if out of memory.
+    #[inline]
+    pub fn push_boxed(&self, boxed: Box<T>) -> Object<'_, Box<T>, A> {
+        self.pool.push(boxed)
+    }
+}
+
+impl<T: ?Sized> Default for DynPool<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: ?Sized, A: GlobalAlloc> From<Pool<Box<T>, A>> for DynPool<T, A> {
+    #[inline]
+    fn from(pool: Pool<Box<T>, A>) -> Self {
+        DynPool {
+            pool,
+        }
+    }
+}
+
+impl<T: ?Sized, A: GlobalAlloc> Deref for DynPool<T, A> {
+    type Target = Pool<Box<T>, A>;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.pool
+    }
+}
+
+impl<T> RecyclePool<T> {
+    /// Creates a new `RecyclePool`.
+    #[inline]
+    pub fn new() -> RecyclePool<T> {
+        RecyclePool::from(Pool::new())
+    }
+}
+
+impl<T, A: GlobalAlloc> RecyclePool<T, A> {
+    /// Takes a value from the `RecyclePool`. A previously used value is reset with
+    /// `Recycle::recycle`, otherwise a new one is created with `T::default()`.
+    ///
+    /// Will panic if out of memory.
+    pub fn acquire(&self) -> Recycled<'_, T, A>
+        where T: Recycle + Default
+    {
+        let object = match self.recycled.try_pop() {
+            Some(slot) => {
+                self.kept.fetch_sub(1, Ordering::Relaxed);
+                let mut object = Object::from_slot(slot as *mut T, &self.pool);
+                // if it panics, the object drops the value and returns the slot
+                object.recycle();
+                object
+            }
+            None => self.pool.push_default(),
+        };
+        let obj = object.obj;
+        mem::forget(object);
+        Recycled {
+            obj,
+            manager: self,
+        }
+    }
+
+    /// The number of values kept for reuse.
+    ///
+    /// Lock-free. Under concurrent usage the value might be outdated by the time it is used.
+    #[inline]
+    pub fn recycled(&self) -> usize {
+        self.kept.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Default for RecyclePool<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, A: GlobalAlloc> From<Pool<T, A>> for RecyclePool<T, A> {
+    #[inline]
+    fn from(pool: Pool<T, A>) -> Self {
+        RecyclePool {
+            pool,
+            recycled: Queue::new(),
+            kept: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl<T, A: GlobalAlloc> Drop for RecyclePool<T, A> {
+    fn drop(&mut self) {
+        while let Some(slot) = self.recycled.try_pop() {
+            drop(Object::from_slot(slot as *mut T, &self.pool));
+        }
+    }
+}
+
+impl<'active, T, A: GlobalAlloc> Drop for Recycled<'active, T, A> {
+    #[inline]
+    fn drop(&mut self) {
+        self.manager.recycled.push(self.obj as usize);
+        self.manager.kept.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl<'active, T, A: GlobalAlloc> Deref for Recycled<'active, T, A> {
+    type Target = T;
+
+    #[allow(clippy::inline_always)]
+    #[inline(always)]
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.obj }
+    }
+}
+
+impl<'active, T, A: GlobalAlloc> DerefMut for Recycled<'active, T, A> {
+    #[allow(clippy::inline_always)]
+    #[inline(always)]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.obj }
+    }
+}
+
+impl<'active, T, A: GlobalAlloc> fmt::Debug for Recycled<'active, T, A>
+    where T: fmt::Debug
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T> SharedPool<T> {
+    /// Creates a new `SharedPool`.
+    #[inline]
+    pub fn new() -> SharedPool<T> {
+        SharedPool::from(Pool::new())
+    }
+}
+
+impl<T, A: GlobalAlloc> SharedPool<T, A> {
+    /// Save the object on the heap, like `Pool::push`.
+    ///
+    /// Will panic if out of memory.
+    #[inline]
+    pub fn push(&self, obj: T) -> OwnedObject<T, A> {
+        self.own(self.pool.push(obj))
+    }
+
+    /// Save the object on the heap, like `Pool::try_push`.
+    #[inline]
+    pub fn try_push(&self, obj: T) -> Result<OwnedObject<T, A>, T> {
+        self.pool.try_push(obj).map(|o| self.own(o))
+    }
+
+    /// Save the object returned by `f` on the heap, like `Pool::push_with`.
+    ///
+    /// Will panic if out of memory.
+    #[inline]
+    pub fn push_with<F>(&self, f: F) -> OwnedObject<T, A>
+        where F: FnOnce() -> T
+    {
+        self.own(self.pool.push_with(f))
+    }
+
+    /// Moves the slot of a borrowing `Object` into an `OwnedObject`.
+    #[inline]
+    fn own(&self, o: Object<'_, T, A>) -> OwnedObject<T, A> {
+        let obj = o.obj;
+        mem::forget(o);
+        OwnedObject {
+            obj,
+            manager: self.pool.clone(),
+        }
+    }
+}
+
+impl<T> Default for SharedPool<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, A: GlobalAlloc> From<Pool<T, A>> for SharedPool<T, A> {
+    #[inline]
+    fn from(pool: Pool<T, A>) -> Self {
+        SharedPool {
+            pool: Arc::new(pool),
+        }
+    }
+}
+
+impl<T, A: GlobalAlloc> Clone for SharedPool<T, A> {
+    #[inline]
+    fn clone(&self) -> Self {
+        SharedPool {
+            pool: self.pool.clone(),
+        }
+    }
+}
+
+impl<T, A: GlobalAlloc> Deref for SharedPool<T, A> {
+    type Target = Pool<T, A>;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.pool
+    }
+}
+
+impl<T, A: GlobalAlloc> OwnedObject<T, A> {
+    /// Returns the owned object from the pool-allocated memory.
+    #[allow(clippy::needless_pass_by_value)]
+    #[inline]
+    pub fn recover(t: Self) -> T {
         let ret = unsafe {
             ptr::read(t.obj)
         };
         t.manager.ret_ptr(t.obj);
+        let manager = unsafe { ptr::read(&t.manager) };
+        mem::forget(t);
+        drop(manager);
         ret
     }
-}
 
-impl<'active, T> Drop for Object<'active, T> {
-    #[inline]
-    fn drop(&mut self) {
+    /// Pins the value in its slot, like `Box::into_pin`.
+    ///
+    /// A value never moves while it is in its slot, and `OwnedObject` keeps the `Pool` alive, so
+    /// the memory can't be reused before the value is dropped, even if the `OwnedObject` is
+    /// forgotten.
+    #[inline]
+    pub fn into_pin(o: Self) -> Pin<Self> {
+        unsafe { Pin::new_unchecked(o) }
+    }
+}
+
+impl<T, A: GlobalAlloc> Drop for OwnedObject<T, A> {
+    #[inline]
+    fn drop(&mut self) {
+        // returns the slot even if the destructor panics
+        let _guard = SlotGuard {
+            slot: self.obj,
+            pool: &self.manager,
+        };
+        unsafe {
+            ptr::drop_in_place(self.obj);
+        }
+    }
+}
+
+impl<T, A: GlobalAlloc> Deref for OwnedObject<T, A> {
+    type Target = T;
+
+    #[allow(clippy::inline_always)]
+    #[inline(always)]
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.obj }
+    }
+}
+
+impl<T, A: GlobalAlloc> DerefMut for OwnedObject<T, A> {
+    #[allow(clippy::inline_always)]
+    #[inline(always)]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.obj }
+    }
+}
+
+impl<T, A: GlobalAlloc> fmt::Debug for OwnedObject<T, A>
+    where T: fmt::Debug
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+unsafe impl<'active, T: Send, A: GlobalAlloc + Sync> Send for Object<'active, T, A> {}
+
+unsafe impl<'active, T: Sync, A: GlobalAlloc + Sync> Sync for Object<'active, T, A> {}
+
+unsafe impl<'active, T: Send, U: Send, A: GlobalAlloc + Sync> Send for MappedObject<'active, T, U, A> {}
+
+unsafe impl<'active, T: Sync, U: Sync, A: GlobalAlloc + Sync> Sync for MappedObject<'active, T, U, A> {}
+
+unsafe impl<'active, T: Send, A: GlobalAlloc + Sync> Send for Recycled<'active, T, A> {}
+
+unsafe impl<'active, T: Sync, A: GlobalAlloc + Sync> Sync for Recycled<'active, T, A> {}
+
+unsafe impl<T: Send, A: GlobalAlloc + Send> Send for Pool<T, A> {}
+
+unsafe impl<T: Send, A: GlobalAlloc + Sync> Sync for Pool<T, A> {}
+
+// the handle to the `Pool` can be dropped on any thread, so it must be both `Send` and `Sync`
+unsafe impl<T: Send, A: GlobalAlloc + Send + Sync> Send for OwnedObject<T, A> {}
+
+unsafe impl<T: Send + Sync, A: GlobalAlloc + Send + Sync> Sync for OwnedObject<T, A> {}
+
+impl<T, A: GlobalAlloc> fmt::Debug for Pool<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let stats = self.stats();
+        write!(f,
+               "Pool {{ {} blocks, {} elements with {} stride in each. {} bytes allocated total for {} possible elements }}",
+               stats.blocks,
+               stats.batch,
+               stats.stride,
+               stats.bytes_allocated,
+               stats.total_slots
+               )
+    }
+}
+
+impl<'active, T, A: GlobalAlloc> fmt::Debug for Object<'active, T, A>
+    where T: fmt::Debug
+{
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<'active, T, A: GlobalAlloc> fmt::Display for Object<'active, T, A>
+    where T: fmt::Display
+{
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<'active, T, A: GlobalAlloc> PartialEq for Object<'active, T, A>
+    where T: PartialEq
+{
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        (**self).eq(&**other)
+    }
+}
+
+impl<'active, T, A: GlobalAlloc> Eq for Object<'active, T, A> where T: Eq {}
+
+impl<'active, T, A: GlobalAlloc> PartialOrd for Object<'active, T, A>
+    where T: PartialOrd
+{
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        (**self).partial_cmp(&**other)
+    }
+}
+
+impl<'active, T, A: GlobalAlloc> Ord for Object<'active, T, A>
+    where T: Ord
+{
+    #[inline]
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        (**self).cmp(&**other)
+    }
+}
+
+impl<'active, T, A: GlobalAlloc> Hash for Object<'active, T, A>
+    where T: Hash
+{
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn object_dereference() {
+        let val = 5u64;
+        let pool = Pool::with_params(false);
+        let mut val2 = pool.push(val);
+        assert_eq!(*val2, val);
+        let val3 = 7u64;
+        *val2 = val3;
+        assert_eq!(*val2, val3);
+    }
+
+    #[test]
+    fn len_and_capacity() {
+        let pool = Pool::with_system_params(false, 64, 1, false);
+        assert_eq!(pool.len(), 0);
+        assert_eq!(pool.capacity(), 0);
+        let a = pool.push(1u64);
+        let b = pool.push(2u64);
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool.capacity(), 8);
+        drop(a);
+        assert_eq!(pool.len(), 1);
+        assert_eq!(Object::recover(b), 2);
+        assert!(pool.is_empty());
+        assert_eq!(pool.capacity(), 8);
+    }
+
+    #[test]
+    fn with_capacity() {
+        let pool = Pool::<u64>::with_capacity(0);
+        assert_eq!(pool.capacity(), 0);
+        let pool = Pool::<u64>::with_capacity(1000);
+        assert_eq!(pool.capacity(), 1024);
+        let vec: Vec<_> = (0..1024).map(|i| pool.push(i)).collect();
+        assert_eq!(pool.capacity(), 1024);
+        assert_eq!(pool.len(), vec.len());
+    }
+
+    #[test]
+    fn object_formatting() {
+        let pool = Pool::new();
+        let val = pool.push(-42i32);
+        assert_eq!(format!("{:?}", val), format!("{:?}", -42i32));
+        assert_eq!(format!("{}", val), format!("{}", -42i32));
+        let pool = Pool::new();
+        let val = pool.push(String::from("pooled \"string\""));
+        assert_eq!(format!("{:?}", val), format!("{:?}", *val));
+        assert_eq!(format!("{}", val), "pooled \"string\"");
+    }
+
+    #[test]
+    fn shrink_to_fit() {
+        let pool = Pool::with_system_params(false, 64, 1, false);
+        let mut vec: Vec<_> = (0..24u64).map(|i| pool.push(i)).collect();
+        assert_eq!(pool.capacity(), 24);
+        pool.shrink_to_fit();
+        assert_eq!(pool.capacity(), 24);
+        vec.truncate(16);
+        vec.drain(..8);
+        pool.shrink_to_fit();
+        assert_eq!(pool.capacity(), 8);
+        for (i, x) in vec.iter().enumerate() {
+            assert_eq!(**x, i as u64 + 8);
+        }
+        vec.truncate(7);
+        pool.shrink_to_fit();
+        assert_eq!(pool.capacity(), 8);
+        vec.clear();
+        pool.shrink_to_fit();
+        assert_eq!(pool.capacity(), 0);
+        assert_eq!(*pool.push(5), 5);
+        assert_eq!(pool.capacity(), 8);
+    }
+
+    #[test]
+    fn zero_sized_type() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct Zst;
+        impl Drop for Zst {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let pool = Pool::new();
+        let vec: Vec<_> = (0..1000).map(|_| pool.push(())).collect();
+        assert_eq!(pool.len(), 1000);
+        drop(vec);
+        assert!(pool.is_empty());
+
+        let pool = Pool::with_params(true);
+        let vec: Vec<_> = (0..1000).map(|_| pool.push(Zst)).collect();
+        assert_eq!(pool.len(), 1000);
+        drop(vec);
+        let zst = Object::recover(pool.push(Zst));
+        assert_eq!(DROPS.load(Ordering::Relaxed), 1000);
+        drop(zst);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 1001);
+        assert!(pool.is_empty());
+        pool.shrink_to_fit();
+        assert_eq!(format!("{:?}", pool), format!("{:?}", Pool::<()>::new()));
+    }
+
+    #[test]
+    fn map_object() {
+        use std::cell::Cell;
+
+        struct Pair<'a> {
+            first: u32,
+            second: String,
+            drops: &'a Cell<usize>,
+        }
+        impl<'a> Drop for Pair<'a> {
+            fn drop(&mut self) {
+                self.drops.set(self.drops.get() + 1);
+            }
+        }
+
+        let drops = Cell::new(0);
+        let pool = Pool::with_system_params(false, 64, 1, false);
+        let pair = pool.push(Pair { first: 3, second: String::from("two"), drops: &drops });
+        let mut second = Object::map(pair, |p| &mut p.second);
+        second.push_str(" and three");
+        assert_eq!(*second, "two and three");
+        assert_eq!(pool.len(), 1);
+        drop(second);
+        assert_eq!(drops.get(), 1);
+        assert!(pool.is_empty());
+
+        let capacity = pool.capacity();
+        for _ in 0..capacity * 2 {
+            let pair = pool.push(Pair { first: 3, second: String::new(), drops: &drops });
+            assert_eq!(*Object::map(pair, |p| &mut p.first), 3);
+        }
+        assert_eq!(drops.get(), 1 + capacity * 2);
+        assert_eq!(pool.capacity(), capacity);
+    }
+
+    #[test]
+    fn batch_allocation() {
+        let pool = Pool::<u64>::with_system_params(true, 64, 64, false);
+        assert_eq!(pool.stride, 64);
+        assert_eq!(pool.batch, 64);
+        assert_eq!(pool.layout.size(), 64 * 64);
+        assert_eq!(pool.layout.align(), 64);
+        let vec: Vec<_> = (0..100).map(|i| pool.push(i)).collect();
+        for (i, x) in vec.iter().enumerate() {
+            assert_eq!(**x, i as u64);
+            assert_eq!(x.obj as usize % 64, 0);
+        }
+        assert_eq!(pool.data.lock().len(), 2);
+    }
+
+    #[test]
+    fn custom_allocator() {
+        use std::alloc::System;
+
+        static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+        static DEALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+        struct Counting;
+        unsafe impl GlobalAlloc for Counting {
+            unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+                ALLOCS.fetch_add(1, Ordering::Relaxed);
+                System.alloc(layout)
+            }
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+                DEALLOCS.fetch_add(1, Ordering::Relaxed);
+                System.dealloc(ptr, layout)
+            }
+        }
+
+        let pool = Pool::with_system_params_and_allocator(false, 64, 1, false, Counting);
+        let mut vec: Vec<_> = (0..20u64).map(|i| pool.push(i)).collect();
+        assert_eq!(ALLOCS.load(Ordering::Relaxed), 3);
+        vec.truncate(8);
+        pool.shrink_to_fit();
+        assert_eq!(DEALLOCS.load(Ordering::Relaxed), 2);
+        vec.extend((0..16).map(|i| pool.push(i)));
+        assert_eq!(ALLOCS.load(Ordering::Relaxed), 5);
+        drop(vec);
+        drop(pool);
+        assert_eq!(DEALLOCS.load(Ordering::Relaxed), 5);
+    }
+
+    #[test]
+    fn oom_handler() {
+        use std::alloc::System;
+
+        // the allocator fails beyond two batches of 8 `u64`s
+        const LIMIT: usize = 128;
+        static USED: AtomicUsize = AtomicUsize::new(0);
+        static HELD: AtomicUsize = AtomicUsize::new(0);
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        struct Limited;
+        unsafe impl GlobalAlloc for Limited {
+            unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+                if USED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size() > LIMIT {
+                    USED.fetch_sub(layout.size(), Ordering::Relaxed);
+                    return ptr::null_mut();
+                }
+                System.alloc(layout)
+            }
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+                USED.fetch_sub(layout.size(), Ordering::Relaxed);
+                System.dealloc(ptr, layout)
+            }
+        }
+
+        let held_layout = Layout::from_size_align(64, 8).unwrap();
+        HELD.store(unsafe { Limited.alloc(held_layout) } as usize, Ordering::Relaxed);
+        let mut pool = PoolBuilder::<u64>::new()
+            .cache_line_size(64)
+            .number_of_sets(1)
+            .allocator(Limited)
+            .build();
+        pool.set_oom_handler(move || {
+            CALLS.fetch_add(1, Ordering::Relaxed);
+            match HELD.swap(0, Ordering::Relaxed) {
+                0 => OomAction::Abort,
+                held => {
+                    unsafe { Limited.dealloc(held as *mut u8, held_layout) };
+                    OomAction::Retry
+                }
+            }
+        });
+
+        let mut vec: Vec<_> = (0..8u64).map(|i| pool.push(i)).collect();
+        assert_eq!(CALLS.load(Ordering::Relaxed), 0);
+        // the second batch fits after the handler freed the held memory
+        vec.extend((8..16).map(|i| pool.push(i)));
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+        assert_eq!(pool.capacity(), 16);
+        // nothing left to free
+        assert_eq!(pool.try_push(16).unwrap_err(), 16);
+        assert_eq!(CALLS.load(Ordering::Relaxed), 2);
+        assert_eq!(pool.capacity(), 16);
+        drop(vec);
+    }
+
+    // the pool's interior mutability doesn't affect the hash or order of an `Object`
+    #[allow(clippy::mutable_key_type)]
+    #[test]
+    fn object_comparison() {
+        use std::collections::{BTreeSet, HashSet};
+
+        let pool = Pool::new();
+        let a = pool.push(String::from("a"));
+        let b = pool.push(String::from("b"));
+        let other_a = pool.push(String::from("a"));
+        assert_eq!(a, other_a);
+        assert!(a < b);
+
+        let mut hash_set = HashSet::new();
+        assert!(hash_set.insert(pool.push(String::from("a"))));
+        assert!(hash_set.insert(pool.push(String::from("b"))));
+        assert!(!hash_set.insert(pool.push(String::from("a"))));
+        assert!(hash_set.contains(&a));
+        assert_eq!(hash_set.len(), 2);
+
+        let mut tree_set = BTreeSet::new();
+        tree_set.insert(b);
+        tree_set.insert(a);
+        assert!(!tree_set.insert(other_a));
+        let ordered: Vec<_> = tree_set.iter().map(|x| x.as_str()).collect();
+        assert_eq!(ordered, ["a", "b"]);
+    }
+
+    #[test]
+    fn leak() {
+        let pool = Pool::with_system_params(false, 64, 1, false);
+        let leaked = Object::leak(pool.push(String::from("leaked")));
+        leaked.push_str(" forever");
+        let leaked_ptr = leaked as *mut String;
+        for _ in 0..3 {
+            let vec: Vec<_> = (0..20).map(|_| pool.push(String::new())).collect();
+            assert!(vec.iter().all(|x| x.obj != leaked_ptr));
+        }
+        pool.shrink_to_fit();
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.capacity(), pool.batch);
+        assert_eq!(leaked, "leaked forever");
+        // the destructor doesn't run, so the string's own memory is reclaimed manually
+        unsafe { ptr::drop_in_place(leaked_ptr) };
+    }
+
+    #[test]
+    fn thread_local_slots() {
+        use crossbeam::scope;
+
+        let pool = Pool::with_system_params(false, 64, 1, false);
+        let first = pool.push(1u64);
+        let slot = first.obj;
+        drop(first);
+        let second = pool.push(2u64);
+        assert_eq!(second.obj, slot);
+        assert_eq!(Object::recover(second), 2);
+        assert_eq!(pool.push(3u64).obj, slot);
+
+        scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let vec: Vec<_> = (0..20u64).map(|i| pool.push(i)).collect();
+                    drop(vec);
+                    for i in 0..100 {
+                        assert_eq!(*pool.push(i), i);
+                    }
+                });
+            }
+        });
+        // the threads exited, so all their slots are back in the shared free list
+        pool.shrink_to_fit();
+        assert_eq!(pool.capacity(), 0);
+    }
+
+    #[test]
+    fn push_with() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let pool = Pool::new();
+        let big = pool.push_with(|| [7u64; 4096]);
+        assert!(big.iter().all(|x| *x == 7));
+        let in_place = unsafe {
+            pool.push_in_place(|slot| {
+                for i in 0..4096 {
+                    (*slot)[i] = i as u64;
+                }
+            })
+        };
+        assert!(in_place.iter().enumerate().all(|(i, x)| *x == i as u64));
+        assert_eq!(pool.len(), 2);
+
+        let result = catch_unwind(AssertUnwindSafe(|| pool.push_with(|| panic!("construction failed"))));
+        assert!(result.is_err());
+        assert_eq!(pool.len(), 2);
+        let capacity = pool.capacity();
+        let again = pool.push_with(|| [1u64; 4096]);
+        assert_eq!(pool.capacity(), capacity);
+        assert_eq!(again[4095], 1);
+    }
+
+    #[test]
+    fn panicking_destructor() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        struct Bomb(u32);
+        impl Drop for Bomb {
+            fn drop(&mut self) {
+                panic!("Bomb {} exploded", self.0);
+            }
+        }
+
+        let pool = Pool::with_system_params(false, 64, 1, false);
+        let bomb = pool.push(Bomb(1));
+        let slot = bomb.obj;
+        assert!(catch_unwind(AssertUnwindSafe(move || drop(bomb))).is_err());
+        assert!(pool.is_empty());
+        let bomb = pool.push(Bomb(2));
+        assert_eq!(bomb.obj, slot);
+        let bomb = Object::map(bomb, |b| &mut b.0);
+        assert!(catch_unwind(AssertUnwindSafe(move || drop(bomb))).is_err());
+        assert!(pool.is_empty());
+        let bomb = pool.push(Bomb(3));
+        assert_eq!(bomb.obj, slot);
+        assert_eq!(pool.capacity(), pool.batch);
+        mem::forget(Object::recover(bomb));
+    }
+
+    #[test]
+    fn geometry() {
+        let pool = Pool::<u64>::with_system_params(true, 64, 64, false);
+        assert_eq!(pool.batch_size(), 64);
+        assert_eq!(pool.stride(), 64);
+        assert_eq!(pool.block_size(), 4096);
+        let pool = Pool::<[u8; 24]>::with_system_params(false, 128, 2, false);
+        assert_eq!(pool.batch_size(), 10);
+        assert_eq!(pool.stride(), 24);
+        assert_eq!(pool.block_size(), 240);
+    }
+
+    #[test]
+    fn with_allocator_geometry() {
+        let pool = Pool::<u64, _>::with_allocator(Heap);
+        let default = Pool::<u64>::new();
+        assert_eq!(pool.stride(), default.stride());
+        assert_eq!(pool.batch_size(), default.batch_size());
+    }
+
+    #[test]
+    fn detached_slots() {
+        let pool = Pool::with_system_params(false, 64, 1, false);
+        let vec: Vec<_> = (0..20u64).map(|i| pool.push(i)).collect();
+        let capacity = pool.capacity();
+        let (values, slots): (Vec<_>, Vec<_>) = vec.into_iter().map(Object::recover_detached).unzip();
+        assert_eq!(values, (0..20).collect::<Vec<_>>());
+        assert!(pool.is_empty());
+        let slots = ::std::thread::spawn(move || slots).join().unwrap();
+        pool.return_slots(slots);
+        let vec: Vec<_> = (0..20u64).map(|i| pool.push(i)).collect();
+        assert_eq!(pool.capacity(), capacity);
+        drop(vec);
+
+        let zst_pool = Pool::new();
+        let (_, slot) = Object::recover_detached(zst_pool.push(()));
+        zst_pool.return_slots(Some(slot));
+        assert!(zst_pool.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "DetachedSlot returned to a different Pool")]
+    fn detached_slot_wrong_pool() {
+        let pool = Pool::new();
+        let other = Pool::new();
+        let (_, slot) = Object::recover_detached(pool.push(1));
+        other.return_slots(Some(slot));
+    }
+
+    #[test]
+    fn push_batch() {
+        let pool = Pool::with_system_params(false, 64, 1, false);
+        let first = pool.push(String::from("first"));
+        let vec = pool.push_batch((0..100).map(|i| i.to_string()));
+        assert_eq!(vec.len(), 100);
+        for (i, x) in vec.iter().enumerate() {
+            assert_eq!(**x, i.to_string());
+        }
+        assert_eq!(pool.len(), 101);
+        assert_eq!(pool.capacity(), 101usize.div_ceil(pool.batch_size()) * pool.batch_size());
+        drop(vec);
+        let capacity = pool.capacity();
+        let vec = pool.push_batch((0..100).map(|i| i.to_string()));
+        assert_eq!(pool.capacity(), capacity);
+        assert_eq!(*vec[99], "99");
+        drop(first);
+        assert!(pool.push_batch(Vec::new()).is_empty());
+
+        let zst_pool = Pool::new();
+        let zsts = zst_pool.push_batch(vec![(); 10]);
+        assert_eq!(zsts.len(), 10);
+        assert_eq!(zst_pool.len(), 10);
+    }
+
+    #[test]
+    fn into_box() {
+        let pool = Pool::new();
+        let obj = pool.push(String::from("boxed"));
+        let slot = obj.obj;
+        let boxed: Box<String> = Object::into_box(obj);
+        assert_eq!(*boxed, "boxed");
+        assert!(pool.is_empty());
+        assert_eq!(pool.push(String::new()).obj, slot);
+    }
+
+    #[test]
+    fn adopt() {
+        let pool = Pool::new();
+        let boxes: Vec<Box<String>> = (0..100).map(|i| Box::new(i.to_string())).collect();
+        let vec: Vec<_> = boxes.into_iter().map(|b| pool.adopt(b)).collect();
+        assert_eq!(pool.len(), 100);
+        let strings: Vec<String> = vec.into_iter().map(Object::recover).collect();
+        assert_eq!(strings, (0..100).map(|i| i.to_string()).collect::<Vec<_>>());
+        assert!(pool.is_empty());
+
+        let obj = pool.adopt(Object::into_box(pool.push(String::from("round trip"))));
+        assert_eq!(*obj, "round trip");
+        assert_eq!(pool.len(), 1);
+
+        let zst_pool = Pool::new();
+        let _zst = zst_pool.adopt(Box::new(()));
+        assert_eq!(zst_pool.len(), 1);
+    }
+
+    #[test]
+    fn for_each_live() {
+        let pool = Pool::with_system_params(false, 64, 1, true);
+        let mut vec: Vec<_> = (0..50u32).map(|i| pool.push(i)).collect();
+        let odd: Vec<_> = vec.drain(..).filter(|x| **x % 2 == 1).collect();
+        let (value, slot) = Object::recover_detached(pool.push(100));
+        assert_eq!(value, 100);
+        Object::leak(pool.push(200));
+        let mut seen = Vec::new();
+        unsafe {
+            pool.for_each_live(|x| {
+                seen.push(*x);
+                *x += 1000;
+            });
+        }
+        seen.sort();
+        let mut expected: Vec<_> = (0..50).filter(|x| x % 2 == 1).collect();
+        expected.push(200);
+        assert_eq!(seen, expected);
+        assert!(odd.iter().all(|x| **x > 1000));
+        pool.return_slots(Some(slot));
+
+        drop(odd);
+        let leaked = pool.len();
+        pool.shrink_to_fit();
+        let mut count = 0;
+        unsafe {
+            pool.for_each_live(|_| count += 1);
+        }
+        assert_eq!(count, leaked);
+
+        let zst_pool = Pool::<()>::with_system_params(false, 64, 64, true);
+        let _zsts = zst_pool.push_batch(vec![(); 3]);
+        let mut count = 0;
+        unsafe {
+            zst_pool.for_each_live(|_| count += 1);
+        }
+        assert_eq!(count, 3);
+    }
+
+    #[cfg(feature = "diagnostics")]
+    #[test]
+    fn free_distribution() {
+        let pool = Pool::with_system_params(false, 64, 1, true);
+        let batch = pool.batch_size();
+        assert!(pool.free_distribution().is_empty());
+        let mut vec: Vec<_> = (0..batch as u64 * 3).map(|i| pool.push(i)).collect();
+        assert_eq!(pool.free_distribution(), vec![0; 3]);
+        // frees every other slot of the second batch and all of the third
+        let third = vec.split_off(batch * 2);
+        let mut second = vec.split_off(batch);
+        let mut i = 0;
+        second.retain(|_| {
+            i += 1;
+            i % 2 == 0
+        });
+        drop(third);
+        assert_eq!(pool.free_distribution(), vec![0, batch - batch / 2, batch]);
+        drop(vec);
+        drop(second);
+        assert_eq!(pool.free_distribution(), vec![batch; 3]);
+    }
+
+    #[cfg(feature = "diagnostics")]
+    #[test]
+    #[should_panic(expected = "Pool::free_distribution requires a Pool created with track_liveness")]
+    fn free_distribution_untracked() {
+        Pool::<u64>::new().free_distribution();
+    }
+
+    #[test]
+    #[should_panic(expected = "requires a Pool created with track_liveness")]
+    fn for_each_live_untracked() {
+        let pool = Pool::<u32>::new();
+        unsafe {
+            pool.for_each_live(|_| ());
+        }
+    }
+
+    #[test]
+    fn shared_pool() {
+        let pool = SharedPool::new();
+        let handle = pool.clone();
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let pool = pool.clone();
+                ::std::thread::spawn(move || (0..10).map(|i| pool.push(t * 10 + i)).collect::<Vec<_>>())
+            })
+            .collect();
+        let mut objects: Vec<OwnedObject<u32>> = Vec::new();
+        for thread in threads {
+            objects.extend(thread.join().unwrap());
+        }
+        assert_eq!(handle.len(), 40);
+        let mut values: Vec<u32> = objects.iter().map(|x| **x).collect();
+        values.sort();
+        assert_eq!(values, (0..40).collect::<Vec<_>>());
+
+        // the objects keep the pool alive after all the handles are dropped
+        drop(pool);
+        drop(handle);
+        let last = objects.pop().unwrap();
+        drop(objects);
+        assert_eq!(OwnedObject::recover(last), 39);
+
+        let pool = SharedPool::from(Pool::new());
+        let obj = pool.push_with(|| String::from("owned"));
+        drop(pool);
+        assert_eq!(format!("{:?}", obj), "\"owned\"");
+    }
+
+    #[test]
+    #[allow(clippy::mutable_key_type)]
+    fn object_conversions() {
+        use std::borrow::{Borrow, BorrowMut};
+        use std::collections::HashSet;
+
+        fn takes(s: impl AsRef<str>) -> usize {
+            s.as_ref().len()
+        }
+        fn takes_string<S: AsRef<String>>(s: S) -> usize {
+            takes(s.as_ref())
+        }
+        fn append<S: AsMut<String>>(mut s: S) {
+            s.as_mut().push('!');
+        }
+
+        let pool = Pool::new();
+        let mut obj = pool.push(String::from("pooled"));
+        assert_eq!(takes_string(&obj), 6);
+        append(&mut obj);
+        assert_eq!(*obj, "pooled!");
+        BorrowMut::<String>::borrow_mut(&mut obj).push('?');
+        assert_eq!(Borrow::<String>::borrow(&obj), "pooled!?");
+
+        let set: HashSet<_> = vec![pool.push(String::from("a")), pool.push(String::from("b"))].into_iter().collect();
+        assert!(set.contains(&String::from("a")));
+        assert!(!set.contains(&String::from("c")));
+    }
+
+    #[test]
+    fn reserve() {
+        let pool = Pool::with_system_params(false, 64, 1, false);
+        let first: Vec<_> = (0..10u64).map(|i| pool.push(i)).collect();
+        pool.reserve(1000);
+        let capacity = pool.capacity();
+        assert!(capacity - pool.len() >= 1000);
+        assert!(capacity - pool.len() < 1000 + pool.batch_size());
+        pool.reserve(1000);
+        pool.reserve(10);
+        assert_eq!(pool.capacity(), capacity);
+        let vec: Vec<_> = {
+            // a push that tried to take the lock would deadlock while it is held
+            let _lock = pool.data.lock();
+            (0..1000u64).map(|i| pool.push(i)).collect()
+        };
+        assert_eq!(pool.len(), 1010);
+        assert_eq!(pool.capacity(), capacity);
+        drop(vec);
+        drop(first);
+    }
+
+    #[test]
+    fn max_capacity() {
+        let pool = Pool::<[u8; 64]>::with_max_capacity(200);
+        let batch = pool.batch_size();
+        assert_eq!(batch, 64);
+        let mut vec = Vec::new();
+        let rejected = loop {
+            match pool.try_push([0; 64]) {
+                Ok(x) => vec.push(x),
+                Err(x) => break x,
+            }
+        };
+        assert_eq!(rejected, [0; 64]);
+        assert_eq!(vec.len(), 192);
+        assert_eq!(pool.capacity(), 192);
+        assert!(pool.try_push([1; 64]).is_err());
+        vec.pop();
+        assert!(pool.try_push([1; 64]).is_ok());
+        assert_eq!(pool.capacity(), 192);
+    }
+
+    #[test]
+    fn concurrent_expand() {
+        use crossbeam::scope;
+        use std::sync::Mutex;
+
+        let pool = Pool::<[u8; 64]>::with_max_capacity(64 * 20);
+        let pushed = Mutex::new(Vec::new());
+        scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    let mut vec = Vec::new();
+                    while let Ok(x) = pool.try_push([1; 64]) {
+                        vec.push(x);
+                    }
+                    pushed.lock().unwrap().append(&mut vec);
+                });
+            }
+        });
+        // every batch was claimed before it was allocated, so the threads together stayed in the limit
+        assert_eq!(pool.capacity(), 64 * 20);
+        let pushed = pushed.into_inner().unwrap();
+        assert_eq!(pool.len(), pushed.len());
+        assert!(pushed.iter().all(|x| **x == [1; 64]));
+    }
+
+    #[test]
+    #[should_panic(expected = "Pool failed to allocate a new batch")]
+    fn max_capacity_push() {
+        let pool = Pool::<u64>::with_max_capacity(0);
+        pool.push(0);
+    }
+
+    #[test]
+    fn push_default() {
+        let pool = Pool::new();
+        let mut obj: Object<Vec<i32>> = pool.push_default();
+        assert!(obj.is_empty());
+        obj.extend(0..10);
+        drop(obj);
+        let obj = pool.push_default();
+        assert!(obj.is_empty());
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn cache_line_detection() {
+        let size = cache_line_size();
+        assert!(size.is_power_of_two());
+        assert_eq!(cache_line_size(), size);
+        assert_eq!(Pool::<u8>::with_params(true).stride(), size);
+        assert_eq!(Pool::<u8>::with_system_params(true, 32, 64, false).stride(), 32);
+    }
+
+    #[test]
+    fn ptr_eq() {
+        let pool = Pool::new();
+        let a = pool.push(5);
+        let b = pool.push(5);
+        assert_eq!(a, b);
+        assert!(!Object::ptr_eq(&a, &b));
+        assert!(Object::ptr_eq(&a, &a));
+    }
+
+    #[test]
+    fn dyn_pool() {
+        trait Shape {
+            fn area(&self) -> u32;
+        }
+        struct Square(u32);
+        impl Shape for Square {
+            fn area(&self) -> u32 {
+                self.0 * self.0
+            }
+        }
+        struct Rect(u32, u32);
+        impl Shape for Rect {
+            fn area(&self) -> u32 {
+                self.0 * self.1
+            }
+        }
+
+        let pool = DynPool::<dyn Shape>::new();
+        let shapes = vec![pool.push_boxed(Box::new(Square(3))), pool.push_boxed(Box::new(Rect(2, 5)))];
+        assert_eq!(shapes.iter().map(|s| s.area()).sum::<u32>(), 19);
+        assert_eq!(pool.len(), 2);
+        let slots: Vec<_> = shapes.iter().map(|s| s.obj).collect();
+        drop(shapes);
+        let shape = pool.push_boxed(Box::new(Rect(1, 1)));
+        assert_eq!(shape.area(), 1);
+        assert!(slots.contains(&shape.obj));
+
+        let strs = DynPool::<str>::new();
+        assert_eq!(&**strs.push_boxed("pooled".into()), "pooled");
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn poisoned_lock() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let pool = Pool::with_system_params(false, 64, 1, false);
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            let _lock = pool.data.lock();
+            panic!("panicked while holding the lock");
+        }));
+        assert!(result.is_err());
+        assert!(pool.data.is_poisoned());
+        let vec: Vec<_> = (0..100u64).map(|i| pool.push(i)).collect();
+        assert_eq!(pool.data.lock().len(), 13);
+        drop(vec);
+        pool.shrink_to_fit();
+        assert_eq!(pool.capacity(), 0);
+    }
+
+    #[test]
+    fn recycle_pool() {
+        let pool = RecyclePool::<Vec<u32>>::new();
+        let mut first = pool.acquire();
+        assert!(first.is_empty());
+        first.extend(0..100);
+        let slot = first.obj;
+        drop(first);
+        assert_eq!(pool.recycled(), 1);
+        let second = pool.acquire();
+        assert_eq!(second.obj, slot);
+        assert!(second.is_empty());
+        assert!(second.capacity() >= 100);
+        assert_eq!(pool.recycled(), 0);
+        let third = pool.acquire();
+        assert!(third.capacity() < 100);
+        drop(second);
+        drop(third);
+        assert_eq!(pool.recycled(), 2);
+    }
+
+    #[test]
+    fn recycle_pool_drops() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        #[derive(Default)]
+        struct Counted(bool);
+        impl Recycle for Counted {
+            fn recycle(&mut self) {
+                assert!(!self.0, "recycling a broken value");
+            }
+        }
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let pool = RecyclePool::new();
+        let vec: Vec<Recycled<Counted>> = (0..10).map(|_| pool.acquire()).collect();
+        drop(vec);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 0);
+        let mut vec: Vec<Recycled<Counted>> = (0..10).map(|_| pool.acquire()).collect();
+        assert_eq!(pool.recycled(), 0);
+        vec[0].0 = true;
+        drop(vec.remove(0));
+        assert!(catch_unwind(AssertUnwindSafe(|| pool.acquire())).is_err());
+        assert_eq!(DROPS.load(Ordering::Relaxed), 1);
+        assert_eq!(pool.recycled(), 0);
+        drop(vec);
+        assert_eq!(pool.recycled(), 9);
+        drop(pool);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 10);
+    }
+
+    #[test]
+    fn stats() {
+        let pool = Pool::<u64>::with_system_params(false, 64, 1, false);
+        assert_eq!(pool.stats(), PoolStats {
+            batch: 8,
+            stride: 8,
+            ..PoolStats::default()
+        });
+        let vec: Vec<_> = (0..10).map(|i| pool.push(i)).collect();
+        assert_eq!(pool.stats(), PoolStats {
+            blocks: 2,
+            batch: 8,
+            stride: 8,
+            bytes_allocated: 128,
+            total_slots: 16,
+            live: 10,
+            free: 6,
+        });
+        drop(vec);
+        assert_eq!(pool.stats().free, 16);
+    }
+
+    #[test]
+    fn contains() {
+        let pool = Pool::new();
+        let other = Pool::new();
+        let vec: Vec<_> = (0..100u32).map(|i| pool.push(i)).collect();
+        let foreign = other.push(0);
+        assert!(vec.iter().all(|o| pool.contains(o)));
+        assert!(!pool.contains(&foreign));
+        assert!(!other.contains(&vec[0]));
+        assert!(other.contains(&foreign));
+
+        let zst_pool = Pool::new();
+        let other_zst_pool = Pool::new();
+        let zst = zst_pool.push(());
+        assert!(zst_pool.contains(&zst));
+        assert!(!other_zst_pool.contains(&zst));
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "Object used after its slot was released")]
+    fn stale_object() {
+        let pool = Pool::new();
+        let obj = pool.push(1u64);
+        // a duplicate made by unsafe code, which must never be dropped
+        let stale = mem::ManuallyDrop::new(unsafe { ptr::read(&obj) });
+        assert_eq!(**stale, 1);
+        assert_eq!(Object::recover(obj), 1);
+        let new = pool.push(2u64);
+        assert_eq!(new.obj, stale.obj);
+        assert_eq!(*new, 2);
+        let _ = **stale;
+    }
+
+    #[test]
+    fn builder() {
+        let built = PoolBuilder::<[u8; 24]>::new()
+            .align_to_cache(true)
+            .cache_line_size(32)
+            .number_of_sets(4)
+            .track_liveness(true)
+            .build();
+        let positional = Pool::<[u8; 24]>::with_system_params(true, 32, 4, true);
+        assert_eq!(built.stats(), positional.stats());
+        assert!(built.liveness.is_some());
+
+        let pool = PoolBuilder::<u64>::new()
+            .cache_line_size(64)
+            .number_of_sets(4)
+            .max_capacity(200)
+            .prewarm(100)
+            .build();
+        assert_eq!(pool.batch_size(), 32);
+        assert_eq!(pool.capacity(), 128);
+        let vec: Vec<_> = (0..192).map(|i| pool.push(i)).collect();
+        assert_eq!(pool.capacity(), 192);
+        assert!(pool.try_push(0).is_err());
+        drop(vec);
+    }
+
+    #[test]
+    fn return_hook() {
+        let returned = Arc::new(AtomicUsize::new(0));
+        let counter = returned.clone();
+        let pool = Pool::with_return_hook(Box::new(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }));
+        let mut vec: Vec<_> = (0..100u64).map(|i| pool.push(i)).collect();
+        vec.truncate(40);
+        assert_eq!(returned.load(Ordering::Relaxed), 60);
+        for x in vec.drain(..10) {
+            Object::recover(x);
+        }
+        assert_eq!(returned.load(Ordering::Relaxed), 70);
+        let (_, slot) = Object::recover_detached(vec.pop().unwrap());
+        assert_eq!(returned.load(Ordering::Relaxed), 70);
+        pool.return_slots(Some(slot));
+        assert_eq!(returned.load(Ordering::Relaxed), 71);
+        Object::leak(vec.pop().unwrap());
+        mem::forget(vec);
+        drop(pool);
+        assert_eq!(returned.load(Ordering::Relaxed), 71);
+    }
+
+    /// A future that refers to itself after its first poll, so it must not move after that.
+    struct SelfRef {
+        value: u32,
+        this: *const u32,
+        _pinned: core::marker::PhantomPinned,
+    }
+
+    impl SelfRef {
+        fn new(value: u32) -> Self {
+            SelfRef {
+                value,
+                this: ptr::null(),
+                _pinned: core::marker::PhantomPinned,
+            }
+        }
+    }
+
+    impl core::future::Future for SelfRef {
+        type Output = u32;
+
+        fn poll(self: Pin<&mut Self>, _: &mut core::task::Context<'_>) -> core::task::Poll<u32> {
+            let this = unsafe { self.get_unchecked_mut() };
+            if this.this.is_null() {
+                this.this = &this.value;
+                return core::task::Poll::Pending;
+            }
+            assert_eq!(this.this, &this.value as *const u32);
+            core::task::Poll::Ready(unsafe { *this.this })
+        }
+    }
+
+    #[test]
+    fn pinned() {
+        use core::future::Future;
+        use core::task::{Context, Poll, Waker};
+
+        let mut cx = Context::from_waker(Waker::noop());
+        let pool = Pool::new();
+        let mut obj = pool.push(SelfRef::new(5));
         unsafe {
-            ptr::read(self.obj);
+            assert_eq!(Object::as_pin_mut(&mut obj).poll(&mut cx), Poll::Pending);
+            assert_eq!(Object::as_pin_mut(&mut obj).poll(&mut cx), Poll::Ready(5));
         }
-        self.manager.ret_ptr(self.obj);
+
+        let shared = SharedPool::from(Pool::new());
+        let mut pinned = OwnedObject::into_pin(shared.push(SelfRef::new(7)));
+        assert_eq!(pinned.as_mut().poll(&mut cx), Poll::Pending);
+        drop(shared);
+        assert_eq!(pinned.as_mut().poll(&mut cx), Poll::Ready(7));
     }
-}
 
-impl<'active, T> Deref for Object<'active, T> {
-    type Target = T;
+    #[test]
+    fn growth_policy() {
+        let builder = || PoolBuilder::<u64>::new().cache_line_size(64).number_of_sets(1);
+        let fixed = builder().growth(GrowthPolicy::Fixed(3)).build();
+        assert_eq!(fixed.batch_size(), 8);
+        let _first = fixed.push(0);
+        assert_eq!(fixed.capacity(), 24);
+        let vec: Vec<_> = (0..23).map(|i| fixed.push(i)).collect();
+        assert_eq!(fixed.capacity(), 24);
+        let _next = fixed.push(0);
+        assert_eq!(fixed.capacity(), 48);
+        drop(vec);
 
-    #[allow(inline_always)]
-    #[inline(always)]
-    fn deref(&self) -> &Self::Target {
-        unsafe { &*self.obj }
+        let geometric = builder().growth(GrowthPolicy::Geometric(2)).build();
+        let mut vec = Vec::new();
+        let mut capacities = Vec::new();
+        for i in 0..8 * 15 {
+            vec.push(geometric.push(i));
+            if capacities.last() != Some(&geometric.capacity()) {
+                capacities.push(geometric.capacity());
+            }
+        }
+        assert_eq!(capacities, [8, 24, 56, 120]);
+
+        // the growth stops at the maximum capacity
+        let capped = builder().growth(GrowthPolicy::Fixed(4)).max_capacity(20).build();
+        let _first = capped.push(0);
+        assert_eq!(capped.capacity(), 16);
     }
-}
 
-impl<'active, T> DerefMut for Object<'active, T> {
-    #[allow(inline_always)]
-    #[inline(always)]
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { &mut *self.obj }
+    #[test]
+    fn assert_compatible() {
+        let pool = Pool::<u64>::with_system_params(false, 64, 64, false);
+        assert_eq!(pool.slot_alignment(), 8);
+        pool.assert_compatible::<u64>();
+        pool.assert_compatible::<[u8; 8]>();
+        pool.assert_compatible::<u32>();
+        pool.assert_compatible::<()>();
+
+        let aligned = Pool::<[u8; 12]>::with_system_params(true, 64, 64, false);
+        assert_eq!(aligned.slot_alignment(), 64);
+        aligned.assert_compatible::<[u64; 8]>();
+        assert_eq!(Pool::<[u8; 12]>::new().slot_alignment(), 4);
+        assert_eq!(Pool::<()>::new().slot_alignment(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "doesn't fit in the 8 byte slots of the Pool")]
+    fn assert_compatible_size() {
+        Pool::<u64>::new().assert_compatible::<[u8; 9]>();
     }
-}
 
-unsafe impl<'active, T: Send> Send for Object<'active, T> {}
+    #[test]
+    #[should_panic(expected = "needs an alignment of 8, but the slots of the Pool are aligned to 4")]
+    fn assert_compatible_alignment() {
+        Pool::<[u8; 12]>::new().assert_compatible::<u64>();
+    }
+
+    #[test]
+    fn free_order() {
+        let builder = || PoolBuilder::<u64>::new().cache_line_size(64).number_of_sets(4);
+        for &order in &[FreeOrder::Fifo, FreeOrder::Lifo] {
+            let pool = builder().free_order(order).build();
+            let mut vec: Vec<_> = (0..32).map(|i| pool.push(i)).collect();
+            // more than a thread keeps for itself, so most go to the shared free list
+            let freed: Vec<_> = vec.drain(..24).map(|x| x.obj).collect();
+            #[cfg(feature = "std")]
+            let shared = freed[LOCAL_CACHE_SIZE..].to_vec();
+            #[cfg(not(feature = "std"))]
+            let shared = freed;
+            let mut reused = Vec::new();
+            while let Some(x) = pool.free.try_pop() {
+                reused.push(x as *mut u64);
+            }
+            if order == FreeOrder::Fifo {
+                assert_eq!(reused, shared);
+            } else {
+                assert_eq!(reused, shared.into_iter().rev().collect::<Vec<_>>());
+            }
+            for x in reused {
+                pool.free.push(x as usize);
+            }
+        }
+    }
+
+    #[test]
+    fn min_align() {
+        #[repr(align(32))]
+        struct Simd(#[allow(dead_code)] [f32; 3]);
+
+        for &align_to_cache in &[false, true] {
+            let pool = PoolBuilder::<Simd>::new().align_to_cache(align_to_cache).build();
+            let vec: Vec<_> = (0..200).map(|_| pool.push(Simd([0.0; 3]))).collect();
+            assert!(vec.iter().all(|x| (x.obj as usize).is_multiple_of(32)));
+        }
 
-unsafe impl<'active, T: Sync> Sync for Object<'active, T> {}
+        let pool = PoolBuilder::<[u8; 24]>::new().cache_line_size(16).min_align(64).build();
+        assert_eq!(pool.stride(), 64);
+        assert_eq!(pool.slot_alignment(), 64);
+        pool.assert_compatible::<Simd>();
+        let vec: Vec<_> = (0..200).map(|_| pool.push([1; 24])).collect();
+        assert!(vec.iter().all(|x| (x.obj as usize).is_multiple_of(64)));
+    }
 
-unsafe impl<T: Send> Send for Pool<T> {}
+    #[test]
+    fn over_aligned_slots() {
+        #[repr(align(16))]
+        struct Aligned(#[allow(dead_code)] u8);
 
-unsafe impl<T: Send> Sync for Pool<T> {}
+        #[repr(align(16))]
+        struct Wide(#[allow(dead_code)] [u8; 40]);
 
-impl<T> fmt::Debug for Pool<T> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let pages = {
-            self.data.lock().unwrap().len()
+        fn check<T>(pool: &Pool<T>, make: fn(u8) -> T) {
+            assert!(pool.stride().is_multiple_of(mem::align_of::<T>()));
+            let vec: Vec<_> = (0..100).map(|i| pool.push(make(i))).collect();
+            assert!(vec.iter().all(|x| (x.obj as usize).is_multiple_of(mem::align_of::<T>())));
+        }
+
+        for &align_to_cache in &[false, true] {
+            for &cache_line_size in &[1, 8, 16, 64] {
+                let pool = Pool::<Aligned>::with_system_params(align_to_cache, cache_line_size, 4, false);
+                assert_eq!(pool.stride(), if align_to_cache { cache_line_size.max(16) } else { 16 });
+                check(&pool, Aligned);
+                check(&Pool::<Wide>::with_system_params(align_to_cache, cache_line_size, 4, false), |i| Wide([i; 40]));
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Pool requested with a cache_line_size that is not a power of 2")]
+    fn cache_line_size_not_power_of_two() {
+        // a stride of whole 24-byte lines would misalign every other slot of a 32-aligned type
+        #[repr(align(32))]
+        struct Simd(#[allow(dead_code)] [u8; 32]);
+
+        PoolBuilder::<Simd>::new().align_to_cache(true).cache_line_size(24).build();
+    }
+
+    #[test]
+    #[should_panic(expected = "Pool requested with a min_align that is not a power of 2")]
+    fn min_align_not_power_of_two() {
+        PoolBuilder::<u8>::new().min_align(24).build();
+    }
+
+    #[test]
+    #[should_panic(expected = "Pool requested with a min_align below the alignment of the type")]
+    fn min_align_below_type() {
+        PoolBuilder::<u64>::new().min_align(4).build();
+    }
+
+    #[test]
+    fn push_slice() {
+        let pool = Pool::<(u32, f32)>::new();
+        let items: Vec<_> = (0..100).map(|i| (i, i as f32 * 0.5)).collect();
+        let objs = pool.push_slice(&items);
+        assert_eq!(objs.len(), 100);
+        for (obj, item) in objs.iter().zip(&items) {
+            assert_eq!(**obj, *item);
+        }
+        assert!(pool.push_slice(&[]).is_empty());
+
+        let zst = Pool::<()>::new();
+        assert_eq!(zst.push_slice(&[(), (), ()]).len(), 3);
+    }
+
+    #[test]
+    fn replace() {
+        let pool = Pool::new();
+        let mut obj = pool.push(String::from("old"));
+        let addr = &*obj as *const String;
+        assert_eq!(Object::replace(&mut obj, String::from("new")), "old");
+        assert_eq!(*obj, "new");
+        assert_eq!(&*obj as *const String, addr);
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn empty_batch_policy() {
+        let mut pool = Pool::<u64>::new();
+        pool.set_empty_batch_policy(EmptyBatchPolicy::Release);
+        let batch = pool.batch_size();
+        let mut objs: Vec<_> = (0..batch as u64 * 2).map(|i| pool.push(i)).collect();
+        assert_eq!(pool.capacity(), batch * 2);
+        let second = objs.split_off(batch);
+        drop(objs);
+        assert_eq!(pool.capacity(), batch);
+        for (i, obj) in second.iter().enumerate() {
+            assert_eq!(**obj, (batch + i) as u64);
+        }
+        drop(second);
+        assert_eq!(pool.capacity(), 0);
+        assert_eq!(*pool.push(5), 5);
+        assert_eq!(pool.capacity(), 0);
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let pool = PoolBuilder::<u64>::new()
+            .empty_batch_policy(EmptyBatchPolicy::Callback(Box::new(move |stats| {
+                assert_eq!(stats.live, 0);
+                counter.fetch_add(1, Ordering::Relaxed);
+                false
+            })))
+            .build();
+        let (obj, slot) = Object::recover_detached(pool.push(1));
+        assert_eq!(obj, 1);
+        drop(pool.push(2));
+        assert_eq!(calls.load(Ordering::Relaxed), 0);
+        pool.return_slots(Some(slot));
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert_eq!(pool.capacity(), batch);
+    }
+
+    #[test]
+    fn empty_batch_policy_with_gc() {
+        use core::sync::atomic::AtomicBool;
+        use crossbeam::scope;
+
+        let pool = PoolBuilder::<u64>::new()
+            .empty_batch_policy(EmptyBatchPolicy::Release)
+            .number_of_sets(1)
+            .build();
+        let batch = pool.batch_size();
+        let done = AtomicBool::new(false);
+        scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| {
+                    for _ in 0..200 {
+                        drop((0..batch * 3).map(|i| pool.push(i as u64)).collect::<Vec<_>>());
+                    }
+                });
+            }
+            s.spawn(|| {
+                while !done.load(Ordering::Relaxed) {
+                    pool.shrink_to_fit();
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..2000 {
+                    pool.gc();
+                }
+                done.store(true, Ordering::Relaxed);
+            });
+        });
+        assert_eq!(pool.len(), 0);
+
+        // a slot outside every batch is not counted in any of them
+        let obj = pool.push(1);
+        assert_eq!(pool.count_use(ptr::null_mut(), false), None);
+        assert_eq!(pool.count_use((obj.obj as usize + batch * pool.stride()) as *mut u64, false), None);
+        drop(obj);
+        assert_eq!(pool.capacity(), 0);
+    }
+
+    #[test]
+    fn empty_batch_policy_enabled_later() {
+        let mut pool = Pool::<u64>::new();
+        let batch = pool.batch_size();
+        drop((0..batch * 2).map(|_| pool.push(0)).collect::<Vec<_>>());
+        let leaked = Object::leak(pool.push(1));
+        assert_eq!(*leaked, 1);
+        pool.set_empty_batch_policy(EmptyBatchPolicy::Release);
+        assert_eq!(pool.capacity(), batch * 2);
+        // the batch of the leaked object is never empty
+        drop((0..batch * 2 - 1).map(|_| pool.push(0)).collect::<Vec<_>>());
+        assert_eq!(pool.capacity(), batch);
+        assert_eq!(format!("{:?}", EmptyBatchPolicy::default()), "Keep");
+    }
+
+    #[test]
+    fn recycle_into() {
+        let dropped = Arc::new(AtomicUsize::new(0));
+        struct Counted(Arc<AtomicUsize>);
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let pool = Pool::new();
+        let obj = pool.push(Counted(dropped.clone()));
+        let addr = &*obj as *const Counted;
+        let obj = pool.recycle_into(obj, Counted(dropped.clone()));
+        assert_eq!(dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(&*obj as *const Counted, addr);
+        assert_eq!(pool.len(), 1);
+        drop(obj);
+        assert_eq!(dropped.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Object recycled into a different Pool")]
+    fn recycle_into_other_pool() {
+        let a = Pool::new();
+        let b = Pool::new();
+        let obj = a.push(1);
+        b.recycle_into(obj, 2);
+    }
+
+    #[test]
+    fn overflow() {
+        let dropped = Arc::new(AtomicUsize::new(0));
+        struct Counted(u64, Arc<AtomicUsize>);
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                self.1.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let batch = Pool::<Counted>::new().batch_size();
+        let pool = PoolBuilder::<Counted>::new().max_capacity(batch).overflow(true).build();
+        let mut objs: Vec<_> = (0..batch as u64 + 3).map(|i| pool.push(Counted(i, dropped.clone()))).collect();
+        assert_eq!(pool.capacity(), batch);
+        assert_eq!(pool.len(), batch + 3);
+        assert_eq!(pool.stats().free, 0);
+        for (i, obj) in objs.iter().enumerate() {
+            assert_eq!(obj.0, i as u64);
+            assert!(pool.contains(obj));
+        }
+
+        // both kinds of slots are dropped and freed
+        let overflowed = objs.split_off(batch);
+        drop(objs.pop());
+        drop(overflowed);
+        assert_eq!(dropped.load(Ordering::Relaxed), 4);
+        assert_eq!(pool.len(), batch - 1);
+
+        let batched = pool.push_batch((0..3).map(|i| Counted(i, dropped.clone())));
+        assert_eq!(pool.len(), batch + 2);
+        assert_eq!(pool.capacity(), batch);
+        let (value, slot) = Object::recover_detached(pool.push(Counted(7, dropped.clone())));
+        assert_eq!(value.0, 7);
+        pool.return_slots(Some(slot));
+        assert_eq!(Object::recover(pool.push(Counted(8, dropped.clone()))).0, 8);
+        assert_eq!(pool.len(), batch + 2);
+        drop(batched);
+        drop(objs);
+        assert_eq!(pool.len(), 0);
+        assert_eq!(pool.stats().free, batch);
+
+        // the memory of the values leaked beyond the limit is freed with the `Pool`
+        let pool = PoolBuilder::<u64>::new().max_capacity(batch).overflow(true).build();
+        for obj in pool.push_batch(0..batch as u64 + 2) {
+            Object::leak(obj);
+        }
+        assert_eq!(*Object::leak(pool.push(9)), 9);
+    }
+
+    #[test]
+    fn overflow_disabled() {
+        let batch = Pool::<u64>::new().batch_size();
+        let pool = PoolBuilder::<u64>::new().max_capacity(batch).build();
+        let _objs: Vec<_> = (0..batch as u64).map(|i| pool.push(i)).collect();
+        assert_eq!(pool.try_push(1).err(), Some(1));
+    }
+
+    #[test]
+    fn raw_round_trip() {
+        let pool = Pool::<u64>::new();
+        let raw = Object::into_raw(pool.push(42));
+        assert_eq!(unsafe { *raw }, 42);
+        assert_eq!(pool.len(), 1);
+        let obj = unsafe { pool.from_raw(raw) };
+        assert_eq!(*obj, 42);
+        drop(obj);
+        assert_eq!(pool.len(), 0);
+        // the slot was returned and is reused
+        assert_eq!(&*pool.push(7) as *const u64, raw as *const u64);
+    }
+
+    #[test]
+    fn gc() {
+        let pool = Pool::<u64>::new();
+        assert_eq!(pool.gc(), 0);
+        let batch = pool.batch_size();
+        let mut objs: Vec<_> = (0..batch as u64 * 3).map(|i| pool.push(i)).collect();
+        assert_eq!(pool.gc(), 0);
+
+        // a free slot in every batch is not enough
+        let kept: Vec<_> = objs.drain(..).enumerate().filter(|x| x.0 % batch != 0).map(|x| x.1).collect();
+        assert_eq!(pool.gc(), 0);
+        assert_eq!(pool.capacity(), batch * 3);
+
+        drop(kept);
+        assert_eq!(pool.gc(), pool.block_size() * 3);
+        assert_eq!(pool.capacity(), 0);
+        assert_eq!(pool.gc(), 0);
+        assert_eq!(*pool.push(5), 5);
+
+        let zst = Pool::<()>::new();
+        drop(zst.push(()));
+        assert_eq!(zst.gc(), 0);
+    }
+
+    #[test]
+    fn free_slots() {
+        let pool = Pool::<u64>::new();
+        assert_eq!(pool.free_slots(), 0);
+        let batch = pool.batch_size();
+        let mut objs: Vec<_> = (0..batch as u64 + 10).map(|i| pool.push(i)).collect();
+        assert_eq!(pool.free_slots(), batch - 10);
+        objs.truncate(5);
+        assert_eq!(pool.free_slots(), batch * 2 - 5);
+        objs.extend((0..20).map(|i| pool.push(i)));
+        assert_eq!(pool.free_slots(), batch * 2 - 25);
+
+        let pool = PoolBuilder::<u64>::new().max_capacity(batch).overflow(true).build();
+        let objs: Vec<_> = (0..batch as u64 + 3).map(|i| pool.push(i)).collect();
+        assert_eq!(pool.free_slots(), 0);
+        drop(objs);
+        assert_eq!(pool.free_slots(), batch);
+
+        let zst = Pool::<()>::new();
+        let _obj = zst.push(());
+        assert_eq!(zst.free_slots(), usize::MAX);
+    }
+
+    #[test]
+    #[should_panic(expected = "Pool requested with number_of_sets * cache_line_size overflowing usize")]
+    fn sets_size_overflow() {
+        Pool::<u64>::with_system_params(false, 64, usize::MAX / 32, false);
+    }
+
+    #[test]
+    #[should_panic(expected = "Pool requested with bad system cache parameters")]
+    fn batch_too_large() {
+        PoolBuilder::<u64>::new().cache_line_size(64).number_of_sets(usize::MAX / 64).build();
+    }
+
+    #[test]
+    fn rebuild_free_list() {
+        let mut pool = PoolBuilder::<u64>::new().track_liveness(true).build();
+        let batch = pool.batch_size();
+        let kept: Vec<_> = {
+            let raw: Vec<_> = (0..batch as u64 * 2).map(|i| Object::into_raw(pool.push(i))).collect();
+            for slot in &raw[batch..] {
+                drop(unsafe { pool.from_raw(*slot) });
+            }
+            assert_eq!(pool.clear_free_list(), batch);
+            assert_eq!(pool.clear_free_list(), 0);
+            raw[..batch].to_vec()
         };
-        write!(f,
-               "Pool {{ {} blocks, {} elements with {} stride in each. {} bytes allocated total for {} possible elements }}",
-               pages,
-               self.batch,
-               self.stride,
-               pages * self.layout.size(),
-               pages * self.batch
-               )
+
+        pool.rebuild_free_list();
+        assert_eq!(pool.capacity(), batch * 2);
+        let new: Vec<_> = (0..batch as u64).map(|i| pool.push(i)).collect();
+        assert_eq!(pool.capacity(), batch * 2);
+        for obj in &new {
+            assert!(!kept.contains(&(&**obj as *const u64 as *mut u64)));
+        }
+        for (i, slot) in kept.into_iter().enumerate() {
+            assert_eq!(*unsafe { pool.from_raw(slot) }, i as u64);
+        }
     }
-}
 
-// impl<T> fmt::Debug for Object<T>
-// where T: fmt::Debug
-// {
-// #[inline]
-// fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-// (**self).fmt(f)
-// }
-// }
-//
-// impl<T> fmt::Display for Object<T>
-// where T: fmt::Display
-// {
-// #[inline]
-// fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-// (**self).fmt(f)
-// }
-// }
+    #[test]
+    #[should_panic(expected = "DetachedSlot returned to a different Pool")]
+    fn rebuild_free_list_disowns_detached() {
+        let mut pool = PoolBuilder::<u64>::new().track_liveness(true).build();
+        let (_, slot) = Object::recover_detached(pool.push(1));
+        pool.rebuild_free_list();
+        pool.return_slots(Some(slot));
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn shared_object() {
+        use crossbeam::scope;
+
+        let pool = Pool::new();
+        let mut o = pool.push(vec![1u64, 2, 3]);
+        o.push(4);
+        assert_eq!(Object::as_shared(&o), &[1, 2, 3, 4]);
+
+        let shared = Object::into_shared(o);
+        let sum = AtomicUsize::new(0);
+        scope(|s| {
+            for _ in 0..4 {
+                let shared = shared.clone();
+                let sum = &sum;
+                s.spawn(move || {
+                    let total: u64 = shared.iter().sum();
+                    sum.fetch_add(total as usize, Ordering::Relaxed);
+                });
+            }
+        });
+        assert_eq!(sum.load(Ordering::Relaxed), 40);
+        assert_eq!(SharedObject::strong_count(&shared), 1);
+
+        let other = shared.clone();
+        assert!(SharedObject::ptr_eq(&shared, &other));
+        let shared = SharedObject::try_unwrap(shared).unwrap_err();
+        drop(other);
+        let o = SharedObject::try_unwrap(shared).unwrap();
+        assert_eq!(Object::recover(o), vec![1, 2, 3, 4]);
+        assert_eq!(pool.len(), 0);
+    }
 
     #[test]
-    fn object_dereference() {
-        let val = 5u64;
-        let pool = Pool::with_params(false);
-        let mut val2 = pool.push(val);
-        assert_eq!(*val2, val);
-        let val3 = 7u64;
-        *val2 = val3;
-        assert_eq!(*val2, val3);
+    fn weak_object() {
+        let pool = PoolBuilder::new().track_generations(true).build();
+        let first = pool.push(String::from("first"));
+        let weak = Object::downgrade(&first);
+        assert!(weak.is_alive(&pool));
+        assert_eq!(unsafe { weak.upgrade(&pool) }.map(String::as_str), Some("first"));
+
+        let slot = first.obj;
+        drop(first);
+        assert!(!weak.is_alive(&pool));
+        assert!(unsafe { weak.upgrade(&pool) }.is_none());
+
+        // the slot is reused by another value, which the old handle doesn't see
+        let second = pool.push(String::from("second"));
+        assert_eq!(second.obj, slot);
+        let second_weak = Object::downgrade(&second);
+        assert!(!weak.is_alive(&pool));
+        assert!(second_weak.is_alive(&pool));
+
+        // a handle never upgrades in another `Pool`
+        let other = PoolBuilder::new().track_generations(true).build();
+        let _third = other.push(String::from("third"));
+        assert!(!second_weak.is_alive(&other));
+
+        // nor after its batch was released
+        drop(second);
+        pool.shrink_to_fit();
+        assert_eq!(pool.capacity(), 0);
+        assert!(!second_weak.is_alive(&pool));
+        let _fourth = pool.push(String::from("fourth"));
+        assert!(!second_weak.is_alive(&pool));
+    }
+
+    #[test]
+    #[cfg(not(debug_assertions))]
+    #[should_panic(expected = "Object::downgrade requires a Pool created with track_generations")]
+    fn weak_object_untracked() {
+        let pool = Pool::new();
+        Object::downgrade(&pool.push(1u64));
+    }
+
+    #[test]
+    fn recover_all() {
+        let pool = Pool::new();
+        let objects: Vec<_> = (0..100).map(|i| pool.push(i.to_string())).collect();
+        let capacity = pool.capacity();
+        let values = pool.recover_all(objects);
+        assert!(values.iter().map(|v| v.parse::<i32>().unwrap()).eq(0..100));
+        assert_eq!(pool.len(), 0);
+        // the slots are reused
+        let objects: Vec<_> = (0..100).map(|i| pool.push(i.to_string())).collect();
+        assert_eq!(pool.capacity(), capacity);
+        drop(objects);
+        pool.shrink_to_fit();
+        assert_eq!(pool.capacity(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Object recovered into a different Pool")]
+    fn recover_all_different_pool() {
+        let pool = Pool::new();
+        let other = Pool::new();
+        other.recover_all(vec![pool.push(1u64)]);
+    }
+
+    #[test]
+    fn cast() {
+        let pool = Pool::<[u8; 4]>::new();
+        let bytes = pool.push(0xdead_beefu32.to_ne_bytes());
+        let slot = bytes.obj as usize;
+        let mut value: Object<u32> = unsafe { Object::cast(bytes) };
+        assert_eq!(*value, 0xdead_beef);
+        assert_eq!(&*value as *const u32 as usize, slot);
+        *value = 7;
+        let bytes: Object<[u8; 4]> = unsafe { Object::cast(value) };
+        assert_eq!(*bytes, 7u32.to_ne_bytes());
+        assert_eq!(pool.len(), 1);
+
+        // the cast `Object` returns its slot to the `Pool`
+        drop(unsafe { Object::cast::<u32>(bytes) });
+        assert_eq!(pool.len(), 0);
+        assert_eq!(pool.push([0; 4]).obj as usize, slot);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Object cast to a type of a different size or a larger alignment")]
+    fn cast_different_size() {
+        let pool = Pool::<u32>::new();
+        unsafe { Object::cast::<u64>(pool.push(1)) };
+    }
+
+    #[test]
+    fn batch_bases() {
+        use std::collections::HashSet;
+
+        let pool = Pool::with_system_params(false, 64, 1, false);
+        assert!(pool.batch_bases().is_empty());
+        let objects: Vec<_> = (0..20u64).map(|i| pool.push(i)).collect();
+        let (batch, stride) = (pool.batch_size(), pool.stride());
+        let slots: HashSet<usize> = pool.batch_bases()
+            .into_iter()
+            .flat_map(|base| (0..batch).map(move |i| base as usize + i * stride))
+            .collect();
+        assert_eq!(slots.len(), pool.capacity());
+        assert_eq!(pool.capacity(), 24);
+        assert!(objects.iter().all(|o| slots.contains(&(o.obj as usize))));
+    }
+
+    #[test]
+    // `ByAddress` only hashes the address, not the pool's locks
+    #[allow(clippy::mutable_key_type)]
+    fn by_address() {
+        use std::collections::HashSet;
+
+        // `f64` is neither `Hash` nor `Eq`
+        let pool = Pool::new();
+        let first = pool.push(1.0f64);
+        let second = pool.push(1.0f64);
+        assert_eq!(first, second);
+
+        let mut refs = HashSet::new();
+        assert!(refs.insert(ByAddress(&first)));
+        assert!(!refs.insert(ByAddress(&first)));
+        assert!(refs.insert(ByAddress(&second)));
+        assert_eq!(refs.len(), 2);
+        drop(refs);
+
+        let mut owned = HashSet::new();
+        owned.insert(ByAddress(first));
+        owned.insert(ByAddress(second));
+        assert_eq!(owned.len(), 2);
+        let values: Vec<f64> = owned.into_iter().map(|o| Object::recover(o.into_inner())).collect();
+        assert_eq!(values, [1.0, 1.0]);
+        assert_eq!(pool.len(), 0);
+
+        // zero-sized values share their address, only references to the handles differ
+        let pool = Pool::new();
+        let first = pool.push(());
+        let second = pool.push(());
+        let mut refs = HashSet::new();
+        refs.insert(ByAddress(&first));
+        refs.insert(ByAddress(&second));
+        assert_eq!(refs.len(), 2);
+        drop(refs);
+        let mut owned = HashSet::new();
+        assert!(owned.insert(ByAddress(first)));
+        assert!(!owned.insert(ByAddress(second)));
+        assert_eq!(owned.len(), 1);
+    }
+
+    #[test]
+    fn try_push() {
+        let pool = Pool::new();
+        let vec: Vec<_> = (0..200u32).map(|i| pool.try_push(i).ok().unwrap()).collect();
+        for (i, x) in vec.iter().enumerate() {
+            assert_eq!(**x, i as u32);
+        }
     }
 }
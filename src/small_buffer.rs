@@ -1,162 +1,1815 @@
-#![allow(mutex_atomic)]
+#![allow(clippy::mutex_atomic)]
 //! `SmallBuffer` is useful as a buffer for elements that see little usage.
 //! It has a small capacity inline, so a couple messages will not cause it to allocate memory.
 //! If it receives more data than it can store, it will allocate additional memory to handle it.
 //! It will not deallocate any memory, for cases when it's likely an element that has seen a lot of
-//! usage has a higher chance to continue having high usage.
+//! usage has a higher chance to continue having high usage. `SmallBuffer::clear_and_release`
+//! frees it explicitly.
 
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
 use std::sync::Mutex;
-use std::sync::atomic::{AtomicUsize, AtomicPtr, Ordering};
-use std::mem::uninitialized;
-use std::ptr::{read, write, null_mut};
+use std::sync::atomic::{AtomicBool, AtomicUsize, AtomicPtr, Ordering};
+use std::mem::{self, MaybeUninit};
+use std::ops::{Index, IndexMut};
+use std::ptr::{self, null_mut};
+use std::thread;
+
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::iter::FusedIterator;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+#[cfg(feature = "serde")]
+use serde::de::{SeqAccess, Visitor};
+#[cfg(feature = "rayon")]
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
 
 /// A small inline-allocated buffer with expansion capabilities. Pushing values can be done done asynchronously.
-/// Reading values needs exclusive access. Removing values is only possible by draining the whole buffer.
+/// Removing values needs exclusive access.
+///
+/// Reading values through a shared reference is possible during concurrent pushes: the reader sees
+/// the values counted by `len` when it started, and waits for the ones still being written.
 ///
 /// The buffer is built like a linked list. Pushing many values at a time is discouraged. It fits well for cases where the
 /// usual element count is low, but needs to be robust for the occasional peak.
 ///
-/// Note: currently allocates 16 elements at a time. With `RFC #2000 - Const generics`
-/// it will be possible to customize that number.
-pub struct SmallBuffer<T> {
-    buf: [T; 16],
+/// `N` is the number of elements stored inline, and the number of elements allocated at a time
+/// when the buffer grows. Defaults to 16.
+pub struct SmallBuffer<T, const N: usize = 16> {
+    buf: UnsafeCell<[MaybeUninit<T>; N]>,
+    // set when the value of a slot is written, so readers with `&self` don't see it before that
+    ready: [AtomicBool; N],
     last_free_slot: AtomicUsize,
-    next: AtomicPtr<SmallBuffer<T>>,
+    next: AtomicPtr<SmallBuffer<T, N>>,
     unallocated_next: Mutex<bool>,
+    // only checked by `try_push`, and only in the first block
+    limit: usize,
 }
 
-impl<T> SmallBuffer<T> {
-    /// Creates an empty buffer with an initial capacity of 16.
+impl<T, const N: usize> SmallBuffer<T, N> {
+    /// Creates an empty buffer with an initial capacity of `N`.
     pub fn new() -> Self {
-        let buf = unsafe { uninitialized() };
+        assert!(N != 0, "SmallBuffer requested with N = 0");
         SmallBuffer {
-            buf: buf,
+            buf: UnsafeCell::new([const { MaybeUninit::uninit() }; N]),
+            ready: [const { AtomicBool::new(false) }; N],
             last_free_slot: AtomicUsize::new(0),
             next: AtomicPtr::new(null_mut()),
             unallocated_next: Mutex::new(true),
+            limit: usize::MAX,
         }
     }
 
+    /// Creates an empty buffer that can hold at least `capacity` items without allocating. The
+    /// blocks beyond the inline capacity are allocated up front, like with `reserve`.
+    pub fn with_capacity(capacity: usize) -> Self {
+        let buf = Self::new();
+        buf.reserve(capacity);
+        buf
+    }
+
+    /// Creates an empty buffer where `try_push` rejects items once it holds `limit` of them.
+    ///
+    /// `push` ignores the limit. Concurrent calls to `try_push` alone never take `len` beyond it,
+    /// but pushes made with `push` count toward it and can.
+    pub fn with_limit(limit: usize) -> Self {
+        let mut buf = Self::new();
+        buf.limit = limit;
+        buf
+    }
+
     /// Pushes the item asynchronously, allocating more memory if needed.
     pub fn push(&self, item: T) {
         let index = self.last_free_slot.fetch_add(1, Ordering::AcqRel);
         self.insert_at_index(item, index);
     }
 
-    fn insert_at_index(&self, item: T, index: usize) {
-        if index < 16 {
-            let slot = &self.buf[index] as *const T as *mut T;
-            unsafe { write(slot, item) };
-        } else {
-            let index = index - 16;
+    /// Pushes the item asynchronously if the buffer holds fewer items than its limit, set by
+    /// `with_limit`. Otherwise returns the item back.
+    ///
+    /// The place of the item is reserved with a compare-and-swap on `len`, so concurrent callers
+    /// can't overshoot the limit together, but retry when another push wins the race.
+    pub fn try_push(&self, item: T) -> Result<(), T> {
+        let mut len = self.len();
+        loop {
+            if len >= self.limit {
+                return Err(item);
+            }
+            match self.last_free_slot.compare_exchange_weak(len, len + 1, Ordering::AcqRel, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(current) => len = current,
+            }
+        }
+        self.insert_at_index(item, len);
+        Ok(())
+    }
+
+    /// The number of items in the buffer.
+    ///
+    /// `push` reserves a place for the item before writing it, so during concurrent pushes this is an
+    /// upper bound: some of the counted items might not be written yet.
+    pub fn len(&self) -> usize {
+        self.last_free_slot.load(Ordering::Relaxed)
+    }
+
+    /// The number of slots currently allocated: the inline ones and those of every linked block.
+    ///
+    /// Blocks are only freed by `clear_and_release`, so this shows how much the buffer has grown
+    /// since it was created or last released. Can be called during concurrent pushes, which might
+    /// link new blocks in the meantime.
+    pub fn capacity(&self) -> usize {
+        let mut capacity = N;
+        let mut next = self.next.load(Ordering::Acquire);
+        while !next.is_null() {
+            capacity += N;
+            next = unsafe { (*next).next.load(Ordering::Acquire) };
+        }
+        capacity
+    }
+
+    /// Returns `true` if the buffer has no items. During concurrent pushes, see `len`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes the last pushed item and returns it, or `None` if the buffer is empty.
+    pub fn pop(&mut self) -> Option<T> {
+        let len = self.len();
+        if len == 0 {
+            return None;
+        }
+        let val = {
+            let (block, index) = self.locate(len - 1);
+            block.ready[index].store(false, Ordering::Relaxed);
+            unsafe { (*block.slot(index)).assume_init_read() }
+        };
+        self.last_free_slot.store(len - 1, Ordering::Relaxed);
+        Some(val)
+    }
+
+    /// Removes the item at `index` and returns it, moving the last item in its place, like
+    /// `Vec::swap_remove`. Doesn't keep the order of the items, but doesn't shift them either.
+    ///
+    /// Follows the blocks from the first one to find both items, so it takes `O(len / N)` time.
+    ///
+    /// Panics if `index` is not lower than `len()`.
+    pub fn swap_remove(&mut self, index: usize) -> T {
+        let len = self.len();
+        assert!(index < len, "swap_remove index (is {}) should be < len (is {})", index, len);
+        let last = self.pop().unwrap();
+        if index == len - 1 {
+            return last;
+        }
+        let (block, index) = self.locate(index);
+        mem::replace(unsafe { (*block.slot(index)).assume_init_mut() }, last)
+    }
+
+    /// Returns the last pushed item, or `None` if the buffer is empty.
+    ///
+    /// During concurrent pushes, waits for the last counted item to be written.
+    pub fn peek_last(&self) -> Option<&T> {
+        let len = self.len();
+        if len == 0 {
+            return None;
+        }
+        Some(self.get_ready(len - 1))
+    }
+
+    /// Returns the item at `index`, or `None` if `index` is not lower than `len()`.
+    ///
+    /// Follows the blocks from the first one, so it takes `O(index / N)` time. During concurrent
+    /// pushes, waits for the item to be written.
+    pub fn get(&self, index: usize) -> Option<&T> {
+        if index >= self.len() {
+            return None;
+        }
+        Some(self.get_ready(index))
+    }
+
+    /// Returns a mutable reference to the item at `index`, or `None` if `index` is not lower than
+    /// `len()`.
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        if index >= self.len() {
+            return None;
+        }
+        let (block, index) = self.locate(index);
+        Some(unsafe { (*block.slot(index)).assume_init_mut() })
+    }
+
+    /// Creates an iterator over the items counted by `len` when it was created.
+    ///
+    /// During concurrent pushes, waits for each of those items to be written before returning it.
+    pub fn iter(&self) -> Iter<'_, T, N> {
+        Iter {
+            sb: self,
+            next_index: 0,
+            len: self.len(),
+        }
+    }
+
+    /// Returns the item at `index`, which must be lower than `len()`. If the item isn't written yet
+    /// by its push, waits for it.
+    fn get_ready(&self, mut index: usize) -> &T {
+        let mut block = self;
+        while index >= N {
+            block = block.wait_next();
+            index -= N;
+        }
+        block.wait_ready(index)
+    }
+
+    /// Returns the next block, waiting for the push that allocates it if needed.
+    fn wait_next(&self) -> &Self {
+        loop {
             let next = self.next.load(Ordering::Acquire);
-            unsafe {
-                if !next.is_null() {
-                    (*next).insert_at_index(item, index);
-                } else {
-                    let mut lock = self.unallocated_next.lock().unwrap();
-                    if *lock {
-                        *lock = false;
-                        let b = Box::into_raw(Box::new(Self::new()));
-                        self.next.store(b, Ordering::Release);
-                        (*b).insert_at_index(item, index);
-                    } else {
-                        (*self.next.load(Ordering::Acquire)).insert_at_index(item, index);
-                    }
-                }
+            if !next.is_null() {
+                return unsafe { &*next };
             }
+            thread::yield_now();
+        }
+    }
+
+    /// Reserves the index of the next item without writing it. Used by `HybridBuffer`, which only
+    /// keeps the items at inline indices in the buffer and stores the others itself.
+    pub(crate) fn reserve_index(&self) -> usize {
+        self.last_free_slot.fetch_add(1, Ordering::AcqRel)
+    }
+
+    /// Writes `item` at an inline `index` reserved by `reserve_index`.
+    pub(crate) fn write_inline(&self, item: T, index: usize) {
+        debug_assert!(index < N);
+        self.insert_at_index(item, index);
+    }
+
+    /// Sets the number of items counted by `len`, for `HybridBuffer`.
+    pub(crate) fn set_len(&mut self, len: usize) {
+        *self.last_free_slot.get_mut() = len;
+    }
+
+    /// Creates an iterator over the first `len` items, which must be inline.
+    pub(crate) fn iter_inline(&self, len: usize) -> Iter<'_, T, N> {
+        debug_assert!(len <= N);
+        Iter {
+            sb: self,
+            next_index: 0,
+            len,
+        }
+    }
+
+    /// Returns the inline item at `index`, waiting for its push to write it if needed.
+    fn wait_ready(&self, index: usize) -> &T {
+        while !self.ready[index].load(Ordering::Acquire) {
+            thread::yield_now();
+        }
+        unsafe { (*self.slot(index)).assume_init_ref() }
+    }
+
+    /// Finds the block holding the item at `index` and the index inside that block.
+    /// The block must be allocated.
+    fn locate(&self, mut index: usize) -> (&Self, usize) {
+        let mut block = self;
+        while index >= N {
+            block = unsafe { &*block.next.load(Ordering::Acquire) };
+            index -= N;
+        }
+        (block, index)
+    }
+
+    /// A pointer to the inline slot at `index`, without creating references to the other slots.
+    fn slot(&self, index: usize) -> *mut MaybeUninit<T> {
+        debug_assert!(index < N);
+        unsafe { (self.buf.get() as *mut MaybeUninit<T>).add(index) }
+    }
+
+    fn insert_at_index(&self, item: T, index: usize) {
+        if index < N {
+            unsafe { (*self.slot(index)).write(item) };
+            self.ready[index].store(true, Ordering::Release);
+        } else {
+            self.next_or_alloc().insert_at_index(item, index - N);
+        }
+    }
+
+    /// Returns the next block, allocating it if it doesn't exist yet.
+    fn next_or_alloc(&self) -> &Self {
+        let next = self.next.load(Ordering::Acquire);
+        if !next.is_null() {
+            return unsafe { &*next };
+        }
+        let mut lock = self.unallocated_next.lock().unwrap();
+        if *lock {
+            *lock = false;
+            let b = Box::into_raw(Box::new(Self::new()));
+            self.next.store(b, Ordering::Release);
+        }
+        unsafe { &*self.next.load(Ordering::Acquire) }
+    }
+
+    /// Allocates the blocks needed to push `additional` more items without allocating.
+    ///
+    /// Can be called during concurrent pushes. Blocks are never released, so the memory stays
+    /// reserved after the items are removed.
+    pub fn reserve(&self, additional: usize) {
+        let mut needed = self.len().checked_add(additional).expect("capacity overflow");
+        let mut block = self;
+        while needed > N {
+            block = block.next_or_alloc();
+            needed -= N;
         }
     }
 
     /// Creates a drain iterator. After the iterator is dropped, the buffer is empty.
-    pub fn drain(&mut self) -> Drain<T> {
+    pub fn drain(&mut self) -> Drain<'_, T, N> {
         let len = self.last_free_slot.load(Ordering::Relaxed);
         Drain {
             sb: self,
             next_index: 0,
-            len: len,
+            len,
+            drained: 0,
+        }
+    }
+
+    /// Moves all the items to the end of `vec`, leaving the buffer empty. The allocated memory is
+    /// kept for reuse.
+    ///
+    /// Reserves the space in `vec` once and copies a block at a time, so it's faster than
+    /// `vec.extend(buf.drain())`.
+    pub fn drain_into(&mut self, vec: &mut Vec<T>) {
+        let mut len = *self.last_free_slot.get_mut();
+        vec.reserve(len);
+        *self.last_free_slot.get_mut() = 0;
+        let mut block = self;
+        while len > 0 {
+            let count = len.min(N);
+            for ready in &mut block.ready[..count] {
+                *ready.get_mut() = false;
+            }
+            unsafe {
+                let end = vec.as_mut_ptr().add(vec.len());
+                ptr::copy_nonoverlapping(block.buf.get() as *const T, end, count);
+                vec.set_len(vec.len() + count);
+            }
+            len -= count;
+            if len > 0 {
+                block = unsafe { &mut **block.next.get_mut() };
+            }
+        }
+    }
+
+    /// Moves up to `cap` items from the front of the buffer to `dst`, in order, returning how many
+    /// were moved. The remaining items are moved to the front, and the allocated memory is kept for
+    /// reuse. Meant for handing the items over FFI without going through a `Vec`.
+    ///
+    /// # Safety
+    ///
+    /// `dst` must be valid for writes of `cap` items of `T`, and properly aligned. The moved items
+    /// are owned by the caller afterwards, and the previous content of `dst` is overwritten without
+    /// being dropped.
+    pub unsafe fn drain_into_ptr(&mut self, dst: *mut T, cap: usize) -> usize {
+        let len = *self.last_free_slot.get_mut();
+        let count = len.min(cap);
+        if count == 0 {
+            return 0;
+        }
+        let sb: &Self = self;
+        let mut read = Cursor::new(sb);
+        let mut write = Cursor::new(sb);
+        for i in 0..len {
+            let (block, index) = read.get();
+            read.index += 1;
+            block.ready[index].store(false, Ordering::Relaxed);
+            let slot = block.slot(index);
+            if i < count {
+                ptr::copy_nonoverlapping(slot as *const T, dst.add(i), 1);
+            } else {
+                let (target_block, target_index) = write.get();
+                write.index += 1;
+                ptr::copy_nonoverlapping(slot, target_block.slot(target_index), 1);
+                target_block.ready[target_index].store(true, Ordering::Relaxed);
+            }
+        }
+        sb.last_free_slot.store(len - count, Ordering::Relaxed);
+        count
+    }
+
+    /// Moves all the items of `other` to the end of this buffer, in order, leaving `other` empty.
+    ///
+    /// Takes `O(other.len())` time. The items are moved one by one, since the first items of
+    /// `other` are stored inline and its blocks can't be linked into this buffer. The blocks of
+    /// `other` are kept for its reuse.
+    pub fn append(&mut self, other: &mut Self) {
+        self.reserve(other.len());
+        for x in other.drain() {
+            self.push(x);
+        }
+    }
+
+    /// Creates a drain iterator like `drain`, that also returns whether each item is the first of
+    /// a block: the inline one or one of the allocated ones. Useful for tuning `N`.
+    pub fn drain_with_blocks(&mut self) -> DrainWithBlocks<'_, T, N> {
+        DrainWithBlocks {
+            drain: self.drain(),
+        }
+    }
+
+    /// Drops all the items, leaving the buffer empty. The allocated memory is kept for reuse.
+    pub fn clear(&mut self) {
+        self.drain();
+    }
+
+    /// Drops all the items and frees the allocated blocks, leaving only the inline capacity.
+    ///
+    /// Unlike `clear`, the memory is not kept for reuse, for cases when memory is more important
+    /// than avoiding allocations.
+    pub fn clear_and_release(&mut self) {
+        self.clear();
+        let next = mem::replace(self.next.get_mut(), null_mut());
+        if !next.is_null() {
+            // the blocks are empty, dropping them only frees the chain
+            unsafe { drop(Box::from_raw(next)) };
+        }
+        *self.unallocated_next.get_mut().unwrap() = true;
+    }
+
+    /// Moves the items into a new `Vec`, in the same order. The `Vec` is allocated once with the
+    /// exact capacity, and every block is freed as soon as its items are moved.
+    pub fn into_vec(mut self) -> Vec<T> {
+        let mut len = mem::replace(self.last_free_slot.get_mut(), 0);
+        let mut vec = Vec::with_capacity(len);
+        let count = len.min(N);
+        unsafe {
+            ptr::copy_nonoverlapping(self.buf.get() as *const T, vec.as_mut_ptr(), count);
+            vec.set_len(count);
+        }
+        len -= count;
+        while len > 0 {
+            let mut block = unsafe { Box::from_raw(mem::replace(self.next.get_mut(), null_mut())) };
+            let count = len.min(N);
+            unsafe {
+                let end = vec.as_mut_ptr().add(vec.len());
+                ptr::copy_nonoverlapping(block.buf.get() as *const T, end, count);
+                vec.set_len(vec.len() + count);
+            }
+            len -= count;
+            // the rest of the chain is freed with `self`, the block itself holds no items
+            *self.next.get_mut() = mem::replace(block.next.get_mut(), null_mut());
+        }
+        vec
+    }
+
+    /// Drops all the items like `clear`, returning how many were removed.
+    pub fn drain_count(&mut self) -> usize {
+        let mut drain = self.drain();
+        drain.by_ref().for_each(drop);
+        drain.drained_so_far()
+    }
+
+    /// Keeps only the items for which `f` returns `true`, dropping the rest. The kept items are
+    /// moved towards the front in their original order, and the allocated memory is kept for reuse.
+    ///
+    /// If `f` or a destructor panics, the items that weren't checked yet are kept.
+    pub fn retain<F>(&mut self, mut f: F)
+        where F: FnMut(&T) -> bool
+    {
+        let len = self.last_free_slot.swap(0, Ordering::Relaxed);
+        let mut retain = Retain {
+            sb: self,
+            read: Cursor::new(self),
+            write: Cursor::new(self),
+            processed: 0,
+            kept: 0,
+            len,
+        };
+        while retain.processed < len {
+            let (block, index) = retain.read.get();
+            block.ready[index].store(false, Ordering::Relaxed);
+            let slot = block.slot(index);
+            let keep = f(unsafe { (*slot).assume_init_ref() });
+            retain.processed += 1;
+            retain.read.index += 1;
+            if keep {
+                retain.keep(slot);
+            } else {
+                unsafe { ptr::drop_in_place((*slot).as_mut_ptr()) };
+            }
+        }
+    }
+
+    /// Creates an iterator that gives mutable access to the items, leaving them in the buffer.
+    pub fn iter_mut(&mut self) -> IterMut<'_, T, N> {
+        let len = self.last_free_slot.load(Ordering::Relaxed);
+        IterMut {
+            sb: self,
+            next_index: 0,
+            len,
+            _marker: PhantomData,
         }
     }
 }
 
-impl<T> Drop for SmallBuffer<T> {
+impl<T, const N: usize> Drop for SmallBuffer<T, N> {
     fn drop(&mut self) {
         if self.last_free_slot.load(Ordering::Relaxed) != 0 {
             self.drain();
         }
         let next = self.next.load(Ordering::Relaxed);
         if !next.is_null() {
-            unsafe { Box::from_raw(next) };
+            unsafe { drop(Box::from_raw(next)) };
+        }
+    }
+}
+
+impl<T, const N: usize> Default for SmallBuffer<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Index<usize> for SmallBuffer<T, N> {
+    type Output = T;
+
+    /// Returns the item at `index`, like `get`. Panics if `index` is not lower than `len()`.
+    ///
+    /// During concurrent pushes, `len()` is read once at the call: an item that is being pushed
+    /// concurrently might not be counted yet, and indexing it panics.
+    fn index(&self, index: usize) -> &T {
+        let len = self.len();
+        match self.get(index) {
+            Some(x) => x,
+            None => panic!("index out of bounds: the len is {} but the index is {}", len, index),
+        }
+    }
+}
+
+impl<T, const N: usize> IndexMut<usize> for SmallBuffer<T, N> {
+    /// Returns a mutable reference to the item at `index`, like `get_mut`. Panics if `index` is not
+    /// lower than `len()`.
+    fn index_mut(&mut self, index: usize) -> &mut T {
+        let len = self.len();
+        match self.get_mut(index) {
+            Some(x) => x,
+            None => panic!("index out of bounds: the len is {} but the index is {}", len, index),
+        }
+    }
+}
+
+impl<T: Clone, const N: usize> Clone for SmallBuffer<T, N> {
+    /// Creates a new buffer with clones of the items, in the same order, and the same limit. Only the
+    /// items counted by `len` are cloned, and the clone allocates just the blocks it needs to hold
+    /// them.
+    fn clone(&self) -> Self {
+        let mut buf = Self::new();
+        buf.limit = self.limit;
+        for x in self {
+            buf.push(x.clone());
+        }
+        buf
+    }
+}
+
+impl<T, const N: usize> From<Vec<T>> for SmallBuffer<T, N> {
+    /// Moves the items of `vec` into a new buffer, allocating all the blocks they need at once.
+    fn from(vec: Vec<T>) -> Self {
+        let buf = Self::new();
+        buf.reserve(vec.len());
+        for x in vec {
+            buf.push(x);
+        }
+        buf
+    }
+}
+
+impl<T, const N: usize> From<SmallBuffer<T, N>> for Vec<T> {
+    /// Moves the items of `buf` into a new `Vec`, in the same order, like `into_vec`.
+    fn from(buf: SmallBuffer<T, N>) -> Self {
+        buf.into_vec()
+    }
+}
+
+impl<'a, T: Clone, const N: usize> TryFrom<&'a [T]> for SmallBuffer<T, N> {
+    type Error = CapacityError;
+
+    /// Creates a buffer with clones of the items of `slice`, in the same order, without allocating.
+    /// Fails if `slice` has more than `N` items, the capacity of the inline block.
+    fn try_from(slice: &'a [T]) -> Result<Self, Self::Error> {
+        if slice.len() > N {
+            return Err(CapacityError {
+                len: slice.len(),
+                capacity: N,
+            });
+        }
+        let buf = Self::new();
+        for x in slice {
+            buf.push(x.clone());
         }
+        Ok(buf)
+    }
+}
+
+/// The error of creating a `SmallBuffer` from more items than it can hold inline, returned by
+/// `SmallBuffer::try_from`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CapacityError {
+    /// The number of items given.
+    pub len: usize,
+    /// The number of items the buffer holds inline, `N`.
+    pub capacity: usize,
+}
+
+impl fmt::Display for CapacityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} items don't fit in the capacity of {}", self.len, self.capacity)
+    }
+}
+
+impl Error for CapacityError {}
+
+// The buffer owns its values and the whole `next` chain: a block is only reachable through the
+// `AtomicPtr` of the previous one, and is freed only by its owner. Moving the buffer moves all of
+// them, so it can be sent when the values can.
+unsafe impl<T: Send, const N: usize> Send for SmallBuffer<T, N> {}
+
+// Pushing only needs `&self` and moves values in from any thread, and every pushed value gets its own
+// slot, so sharing the buffer needs the values to be sendable. The `next` blocks are published with
+// `Release` and read with `Acquire`, and are never freed while the buffer is shared. Readers with
+// `&self` only touch slots after their push marked them ready, and share the values between threads,
+// so they must be `Sync`.
+unsafe impl<T: Send + Sync, const N: usize> Sync for SmallBuffer<T, N> {}
+
+/// A position in the block chain of a `SmallBuffer`. The block of an index past the last allocated
+/// block is only looked up when accessed.
+struct Cursor<'a, T: 'a, const N: usize> {
+    block: &'a SmallBuffer<T, N>,
+    index: usize,
+}
+
+impl<'a, T, const N: usize> Cursor<'a, T, N> {
+    fn new(sb: &'a SmallBuffer<T, N>) -> Self {
+        Cursor {
+            block: sb,
+            index: 0,
+        }
+    }
+
+    /// The block of the current position and the index inside it. The block must be allocated.
+    fn get(&mut self) -> (&'a SmallBuffer<T, N>, usize) {
+        if self.index == N {
+            self.block = unsafe { &*self.block.next.load(Ordering::Relaxed) };
+            self.index = 0;
+        }
+        (self.block, self.index)
+    }
+}
+
+/// The state of `SmallBuffer::retain`. The items that weren't checked are moved to their place
+/// when it is dropped, even during a panic.
+struct Retain<'a, T: 'a, const N: usize> {
+    sb: &'a SmallBuffer<T, N>,
+    read: Cursor<'a, T, N>,
+    write: Cursor<'a, T, N>,
+    processed: usize,
+    kept: usize,
+    len: usize,
+}
+
+impl<'a, T, const N: usize> Retain<'a, T, N> {
+    /// Moves the item in `slot`, which was already read, to the next write position.
+    fn keep(&mut self, slot: *mut MaybeUninit<T>) {
+        let (block, index) = self.write.get();
+        let target = block.slot(index);
+        if target != slot {
+            unsafe { ptr::copy_nonoverlapping(slot, target, 1) };
+        }
+        block.ready[index].store(true, Ordering::Relaxed);
+        self.write.index += 1;
+        self.kept += 1;
+    }
+}
+
+impl<'a, T, const N: usize> Drop for Retain<'a, T, N> {
+    fn drop(&mut self) {
+        while self.processed < self.len {
+            let (block, index) = self.read.get();
+            block.ready[index].store(false, Ordering::Relaxed);
+            let slot = block.slot(index);
+            self.processed += 1;
+            self.read.index += 1;
+            self.keep(slot);
+        }
+        self.sb.last_free_slot.store(self.kept, Ordering::Relaxed);
     }
 }
 
 /// A draining iterator. Returns the contained elements one at a time, removing them from the
 /// buffer. If the iterator is dropped, the remaining elements will be dropped and the buffer
 /// returned to an empty state.
-pub struct Drain<'a, T: 'a> {
-    sb: &'a mut SmallBuffer<T>,
+pub struct Drain<'a, T: 'a, const N: usize = 16> {
+    sb: &'a mut SmallBuffer<T, N>,
     next_index: usize,
     len: usize,
+    drained: usize,
+}
+
+impl<'a, T, const N: usize> Drain<'a, T, N> {
+    /// The number of items the iterator returned so far.
+    pub fn drained_so_far(&self) -> usize {
+        self.drained
+    }
 }
 
-impl<'a, T> Iterator for Drain<'a, T> {
+impl<'a, T, const N: usize> Iterator for Drain<'a, T, N> {
     type Item = T;
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.next_index >= self.len {
             return None;
         }
-        let val = {
-            let slot = &mut self.sb.buf[self.next_index];
-            unsafe { read(slot) }
-        };
+        *self.sb.ready[self.next_index].get_mut() = false;
+        let val = unsafe { (*self.sb.slot(self.next_index)).assume_init_read() };
         self.next_index += 1;
+        self.drained += 1;
         if self.next_index >= self.len {
-            (*self.sb).last_free_slot.store(0, Ordering::Relaxed);
-        } else if self.next_index >= 16 {
-            (*self.sb).last_free_slot.store(0, Ordering::Relaxed);
-            self.len -= 16;
-            self.next_index -= 16;
+            self.sb.last_free_slot.store(0, Ordering::Relaxed);
+        } else if self.next_index >= N {
+            self.sb.last_free_slot.store(0, Ordering::Relaxed);
+            self.len -= N;
+            self.next_index -= N;
             unsafe { self.sb = &mut *self.sb.next.load(Ordering::Relaxed) };
         }
         Some(val)
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.len - self.next_index;
+        (remaining, Some(remaining))
+    }
 }
 
-impl<'a, T> Drop for Drain<'a, T> {
+impl<'a, T, const N: usize> ExactSizeIterator for Drain<'a, T, N> {}
+
+impl<'a, T, const N: usize> FusedIterator for Drain<'a, T, N> {}
+
+impl<'a, T, const N: usize> Drop for Drain<'a, T, N> {
     fn drop(&mut self) {
         for _ in self {}
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crossbeam::scope;
+/// A draining iterator that also tells which items start a block, created by
+/// `SmallBuffer::drain_with_blocks`. Returns `(item, is_block_start)`.
+pub struct DrainWithBlocks<'a, T: 'a, const N: usize = 16> {
+    drain: Drain<'a, T, N>,
+}
 
-    #[test]
-    fn multiple_insertion_loops() {
-        let mut buf = SmallBuffer::<i32>::new();
-        {
-            let buf_ref = &buf;
-            scope(|s| {
-                for i in 0..140 {
-                    s.spawn(move || buf_ref.push(i));
-                }
-            });
+impl<'a, T, const N: usize> Iterator for DrainWithBlocks<'a, T, N> {
+    type Item = (T, bool);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let block_start = self.drain.drained.is_multiple_of(N);
+        self.drain.next().map(|x| (x, block_start))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.drain.size_hint()
+    }
+}
+
+impl<'a, T, const N: usize> FusedIterator for DrainWithBlocks<'a, T, N> {}
+
+/// An iterator over the items of a `SmallBuffer`, created by `SmallBuffer::iter`.
+pub struct Iter<'a, T: 'a, const N: usize = 16> {
+    sb: &'a SmallBuffer<T, N>,
+    next_index: usize,
+    len: usize,
+}
+
+impl<'a, T, const N: usize> Iterator for Iter<'a, T, N> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next_index >= self.len {
+            return None;
         }
-        let count = buf.drain().count();
-        assert_eq!(count, 140);
-        {
-            let buf_ref = &buf;
-            scope(|s| {
-                for i in 0..70 {
-                    s.spawn(move || buf_ref.push(i));
-                }
-            });
+        if self.next_index >= N {
+            self.sb = self.sb.wait_next();
+            self.len -= N;
+            self.next_index -= N;
         }
-        let count = buf.drain().count();
-        assert_eq!(count, 70);
+        let val = self.sb.wait_ready(self.next_index);
+        self.next_index += 1;
+        Some(val)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.len - self.next_index;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<'a, T, const N: usize> FusedIterator for Iter<'a, T, N> {}
+
+impl<'a, T, const N: usize> Clone for Iter<'a, T, N> {
+    fn clone(&self) -> Self {
+        Iter {
+            sb: self.sb,
+            next_index: self.next_index,
+            len: self.len,
+        }
+    }
+}
+
+impl<'a, T, const N: usize> IntoIterator for &'a SmallBuffer<T, N> {
+    type Item = &'a T;
+    type IntoIter = Iter<'a, T, N>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, T, const N: usize> IntoIterator for &'a mut SmallBuffer<T, N> {
+    type Item = &'a mut T;
+    type IntoIter = IterMut<'a, T, N>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+/// A mutable iterator over the items of a `SmallBuffer`.
+pub struct IterMut<'a, T: 'a, const N: usize = 16> {
+    sb: *mut SmallBuffer<T, N>,
+    next_index: usize,
+    len: usize,
+    _marker: PhantomData<&'a mut T>,
+}
+
+impl<'a, T, const N: usize> Iterator for IterMut<'a, T, N> {
+    type Item = &'a mut T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next_index >= self.len {
+            return None;
+        }
+        let val = unsafe { (*(*self.sb).slot(self.next_index)).assume_init_mut() };
+        self.next_index += 1;
+        if self.next_index < self.len && self.next_index >= N {
+            self.len -= N;
+            self.next_index -= N;
+            self.sb = unsafe { (*self.sb).next.load(Ordering::Relaxed) };
+        }
+        Some(val)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.len - self.next_index;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<'a, T, const N: usize> FusedIterator for IterMut<'a, T, N> {}
+
+unsafe impl<'a, T: Send, const N: usize> Send for IterMut<'a, T, N> {}
+
+unsafe impl<'a, T: Sync, const N: usize> Sync for IterMut<'a, T, N> {}
+
+impl<T, const N: usize> IntoIterator for SmallBuffer<T, N> {
+    type Item = T;
+    type IntoIter = IntoIter<T, N>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        // the iterator takes over dropping the items
+        let len = self.last_free_slot.swap(0, Ordering::Relaxed);
+        IntoIter {
+            sb: self,
+            next_index: 0,
+            len,
+        }
+    }
+}
+
+/// An iterator that moves the items out of a `SmallBuffer`. The allocated memory of the buffer is
+/// released as the iteration advances. If the iterator is dropped, the remaining elements will be
+/// dropped as well.
+pub struct IntoIter<T, const N: usize = 16> {
+    // the block currently iterated on, owned by the iterator
+    sb: SmallBuffer<T, N>,
+    next_index: usize,
+    len: usize,
+}
+
+impl<T, const N: usize> Iterator for IntoIter<T, N> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next_index >= self.len {
+            return None;
+        }
+        if self.next_index >= N {
+            let next = self.sb.next.swap(null_mut(), Ordering::Relaxed);
+            // the previous block holds no items and no chain now, so it just releases its memory
+            self.sb = unsafe { *Box::from_raw(next) };
+            self.len -= N;
+            self.next_index -= N;
+        }
+        let val = unsafe { (*self.sb.slot(self.next_index)).assume_init_read() };
+        self.next_index += 1;
+        Some(val)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.len - self.next_index;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<T, const N: usize> FusedIterator for IntoIter<T, N> {}
+
+impl<T, const N: usize> Drop for IntoIter<T, N> {
+    fn drop(&mut self) {
+        for _ in self {}
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<T: Send, const N: usize> SmallBuffer<T, N> {
+    /// Creates a parallel draining iterator, like `drain`. Every block is handed to a separate
+    /// rayon task. The buffer is empty as soon as the iterator is created, and the allocated
+    /// memory is kept for reuse. The items that are not consumed are dropped with the iterator.
+    pub fn par_drain<'a>(&'a mut self) -> impl ParallelIterator<Item = T> + 'a {
+        let mut len = mem::replace(self.last_free_slot.get_mut(), 0);
+        let mut blocks = Vec::with_capacity(len.div_ceil(N));
+        let mut block = self;
+        while len > 0 {
+            let count = len.min(N);
+            for ready in &mut block.ready[..count] {
+                *ready.get_mut() = false;
+            }
+            blocks.push(BlockItems {
+                items: block.buf.get() as *mut T,
+                next: 0,
+                len: count,
+                _marker: PhantomData,
+            });
+            len -= count;
+            if len > 0 {
+                block = unsafe { &mut **block.next.get_mut() };
+            }
+        }
+        blocks.into_par_iter().flat_map_iter(|x| x)
+    }
+}
+
+/// The items taken out of a block by `SmallBuffer::par_drain`. The items that are not returned are
+/// dropped with it.
+#[cfg(feature = "rayon")]
+struct BlockItems<'a, T: 'a> {
+    items: *mut T,
+    next: usize,
+    len: usize,
+    _marker: PhantomData<&'a mut T>,
+}
+
+// Owns its items, which are in a block no one else accesses until the iterator is dropped.
+#[cfg(feature = "rayon")]
+unsafe impl<'a, T: Send> Send for BlockItems<'a, T> {}
+
+#[cfg(feature = "rayon")]
+impl<'a, T> Iterator for BlockItems<'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next >= self.len {
+            return None;
+        }
+        let val = unsafe { ptr::read(self.items.add(self.next)) };
+        self.next += 1;
+        Some(val)
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<'a, T> Drop for BlockItems<'a, T> {
+    fn drop(&mut self) {
+        for _ in self {}
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<T: Serialize, const N: usize> Serialize for SmallBuffer<T, N> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, T: Deserialize<'de>, const N: usize> Deserialize<'de> for SmallBuffer<T, N> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SmallBufferVisitor<T, const N: usize>(PhantomData<T>);
+
+        impl<'de, T: Deserialize<'de>, const N: usize> Visitor<'de> for SmallBufferVisitor<T, N> {
+            type Value = SmallBuffer<T, N>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a sequence")
+            }
+
+            fn visit_seq<S: SeqAccess<'de>>(self, mut seq: S) -> Result<Self::Value, S::Error> {
+                let buf = SmallBuffer::new();
+                while let Some(item) = seq.next_element()? {
+                    buf.push(item);
+                }
+                Ok(buf)
+            }
+        }
+
+        deserializer.deserialize_seq(SmallBufferVisitor(PhantomData))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam::scope;
+    use std::rc::Rc;
+
+    #[test]
+    fn multiple_insertion_loops() {
+        let mut buf = SmallBuffer::<i32>::new();
+        {
+            let buf_ref = &buf;
+            scope(|s| {
+                for i in 0..140 {
+                    s.spawn(move || buf_ref.push(i));
+                }
+            });
+        }
+        let count = buf.drain().count();
+        assert_eq!(count, 140);
+        {
+            let buf_ref = &buf;
+            scope(|s| {
+                for i in 0..70 {
+                    s.spawn(move || buf_ref.push(i));
+                }
+            });
+        }
+        let count = buf.drain().count();
+        assert_eq!(count, 70);
+    }
+
+    fn check_inline_capacity<const N: usize>() {
+        let mut buf = SmallBuffer::<String, N>::new();
+        for _ in 0..3 {
+            for i in 0..N * 3 + 1 {
+                buf.push(i.to_string());
+            }
+            assert_eq!(buf.len(), N * 3 + 1);
+            for x in buf.iter_mut() {
+                x.push('!');
+            }
+            let drained: Vec<_> = buf.drain().collect();
+            assert_eq!(drained, (0..N * 3 + 1).map(|i| format!("{}!", i)).collect::<Vec<_>>());
+        }
+        for i in 0..N * 2 + 1 {
+            buf.push(i.to_string());
+        }
+    }
+
+    #[test]
+    fn inline_capacity() {
+        check_inline_capacity::<1>();
+        check_inline_capacity::<4>();
+        check_inline_capacity::<16>();
+        let buf = SmallBuffer::<u8>::new();
+        let _: &SmallBuffer<u8, 16> = &buf;
+    }
+
+    #[test]
+    fn invalid_bit_patterns() {
+        use std::num::NonZeroU32;
+
+        let mut bools = SmallBuffer::<bool, 4>::new();
+        let mut non_zeros = SmallBuffer::<NonZeroU32, 4>::new();
+        for i in 1..10 {
+            bools.push(i % 2 == 0);
+            non_zeros.push(NonZeroU32::new(i).unwrap());
+        }
+        assert_eq!(bools.drain().filter(|x| *x).count(), 4);
+        assert_eq!(non_zeros.drain().map(NonZeroU32::get).sum::<u32>(), 45);
+    }
+
+    #[test]
+    fn pop() {
+        for &count in &[16, 17, 32] {
+            let mut buf = SmallBuffer::<String>::new();
+            assert_eq!(buf.pop(), None);
+            assert_eq!(buf.peek_last(), None);
+            for i in 0..count {
+                buf.push(i.to_string());
+            }
+            for i in (0..count).rev() {
+                assert_eq!(buf.peek_last(), Some(&i.to_string()));
+                assert_eq!(buf.pop(), Some(i.to_string()));
+                assert_eq!(buf.len(), i);
+            }
+            assert_eq!(buf.pop(), None);
+            assert_eq!(buf.peek_last(), None);
+        }
+
+        let mut buf = SmallBuffer::<i32>::new();
+        for i in 0..17 {
+            buf.push(i);
+        }
+        assert_eq!(buf.pop(), Some(16));
+        assert_eq!(buf.pop(), Some(15));
+        buf.push(15);
+        buf.push(16);
+        buf.push(17);
+        assert_eq!(buf.drain().collect::<Vec<_>>(), (0..18).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn iter_mut() {
+        let mut buf = SmallBuffer::<i32>::new();
+        assert_eq!(buf.iter_mut().count(), 0);
+        for i in 0..40 {
+            buf.push(i);
+        }
+        for x in buf.iter_mut() {
+            *x *= 2;
+        }
+        assert_eq!(buf.iter_mut().count(), 40);
+        assert_eq!(buf.len(), 40);
+        let drained: Vec<_> = buf.drain().collect();
+        assert_eq!(drained, (0..40).map(|i| i * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn into_iter() {
+        let buf = SmallBuffer::<String, 4>::new();
+        for i in 0..10 {
+            buf.push(i.to_string());
+        }
+        let collected: Vec<_> = buf.into_iter().collect();
+        assert_eq!(collected, (0..10).map(|i| i.to_string()).collect::<Vec<_>>());
+
+        let drops = Rc::new(());
+        for taken in 0..12 {
+            let buf = SmallBuffer::<Rc<()>, 4>::new();
+            for _ in 0..10 {
+                buf.push(drops.clone());
+            }
+            let mut iter = buf.into_iter();
+            for _ in 0..taken {
+                iter.next();
+            }
+            assert_eq!(Rc::strong_count(&drops), 1 + 10 - taken.min(10));
+            drop(iter);
+            assert_eq!(Rc::strong_count(&drops), 1);
+        }
+    }
+
+    #[test]
+    fn clear() {
+        let drops = Rc::new(());
+        let mut buf = SmallBuffer::<(String, Rc<()>)>::new();
+        for i in 0..40 {
+            buf.push((i.to_string(), drops.clone()));
+        }
+        let next = buf.next.load(Ordering::Relaxed);
+        buf.clear();
+        assert!(buf.is_empty());
+        assert_eq!(Rc::strong_count(&drops), 1);
+        assert_eq!(buf.next.load(Ordering::Relaxed), next);
+        for i in 0..20 {
+            buf.push((i.to_string(), drops.clone()));
+        }
+        assert_eq!(buf.next.load(Ordering::Relaxed), next);
+        assert_eq!(buf.drain().map(|x| x.0).collect::<Vec<_>>(),
+                   (0..20).map(|i| i.to_string()).collect::<Vec<_>>());
+        assert_eq!(Rc::strong_count(&drops), 1);
+    }
+
+    #[test]
+    fn iter() {
+        let buf = SmallBuffer::<String, 4>::new();
+        assert_eq!(buf.iter().count(), 0);
+        for i in 0..10 {
+            buf.push(i.to_string());
+        }
+        let mut iter = buf.iter();
+        assert_eq!(iter.next().map(|x| &**x), Some("0"));
+        buf.push(String::from("late"));
+        assert_eq!(iter.count(), 9);
+        assert_eq!(buf.iter().count(), 11);
+        assert_eq!(buf.peek_last().map(|x| &**x), Some("late"));
+
+        // readers running together with pushes see every value they counted
+        let buf = SmallBuffer::<usize, 4>::new();
+        scope(|s| {
+            for t in 0..4 {
+                let buf = &buf;
+                s.spawn(move || {
+                    for i in 0..100 {
+                        buf.push(t * 100 + i);
+                    }
+                });
+            }
+            for _ in 0..100 {
+                let len = buf.len();
+                assert!(buf.iter().count() >= len);
+            }
+        });
+        let mut values: Vec<_> = buf.iter().cloned().collect();
+        values.sort();
+        assert_eq!(values, (0..400).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn simultaneous_iters() {
+        let mut buf = SmallBuffer::<u32>::new();
+        for i in 0..40 {
+            buf.push(i);
+        }
+        let first = buf.iter();
+        let second = buf.iter();
+        buf.push(40);
+        assert!(first.clone().eq(second.clone()));
+        assert!(first.zip(second).all(|(a, b)| a == b && *a < 40));
+        assert_eq!((&buf).into_iter().count(), 41);
+        for x in &mut buf {
+            *x += 1;
+        }
+        let readers: Vec<u32> = scope(|s| {
+            let handles: Vec<_> = (0..4).map(|_| s.spawn(|| buf.iter().sum::<u32>())).collect();
+            handles.into_iter().map(|h| h.join()).collect()
+        });
+        assert_eq!(readers, vec![(1..42).sum::<u32>(); 4]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        extern crate serde_json;
+
+        let buf = SmallBuffer::<String>::new();
+        assert_eq!(serde_json::to_string(&buf).unwrap(), "[]");
+        for i in 0..40 {
+            buf.push(i.to_string());
+        }
+        let json = serde_json::to_string(&buf).unwrap();
+        let mut copy: SmallBuffer<String> = serde_json::from_str(&json).unwrap();
+        assert_eq!(copy.len(), 40);
+        assert_eq!(copy.drain().collect::<Vec<_>>(), (0..40).map(|i| i.to_string()).collect::<Vec<_>>());
+        let mut empty: SmallBuffer<u8, 4> = serde_json::from_str("[]").unwrap();
+        assert!(empty.drain().next().is_none());
+    }
+
+    #[test]
+    fn retain() {
+        let mut buf = SmallBuffer::<String>::new();
+        buf.retain(|_| false);
+        for i in 0..100 {
+            buf.push(i.to_string());
+        }
+        let next = buf.next.load(Ordering::Relaxed);
+        buf.retain(|x| x.parse::<u32>().unwrap() % 2 == 0);
+        assert_eq!(buf.len(), 50);
+        assert_eq!(buf.iter().count(), 50);
+        assert_eq!(buf.next.load(Ordering::Relaxed), next);
+        let drained: Vec<_> = buf.drain().collect();
+        assert_eq!(drained, (0..100).filter(|i| i % 2 == 0).map(|i| i.to_string()).collect::<Vec<_>>());
+
+        let drops = Rc::new(());
+        let mut buf = SmallBuffer::<(u32, Rc<()>), 4>::new();
+        for i in 0..10 {
+            buf.push((i, drops.clone()));
+        }
+        buf.retain(|x| x.0 > 6);
+        assert_eq!(Rc::strong_count(&drops), 4);
+        buf.retain(|_| true);
+        assert_eq!(buf.iter().map(|x| x.0).collect::<Vec<_>>(), vec![7, 8, 9]);
+    }
+
+    #[test]
+    fn retain_panic() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let mut buf = SmallBuffer::<String, 4>::new();
+        for i in 0..10 {
+            buf.push(i.to_string());
+        }
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            buf.retain(|x| {
+                assert!(x != "5");
+                x.len() % 2 == 0
+            })
+        }));
+        assert!(result.is_err());
+        let rest: Vec<_> = buf.drain().collect();
+        assert_eq!(rest, vec!["5", "6", "7", "8", "9"]);
+    }
+
+    #[test]
+    fn drain_count() {
+        let mut buf = SmallBuffer::<String>::new();
+        assert_eq!(buf.drain_count(), 0);
+        for i in 0..70 {
+            buf.push(i.to_string());
+        }
+        assert_eq!(buf.drain_count(), 70);
+        assert!(buf.is_empty());
+
+        for i in 0..20 {
+            buf.push(i.to_string());
+        }
+        let mut drain = buf.drain();
+        assert_eq!(drain.drained_so_far(), 0);
+        drain.nth(17);
+        assert_eq!(drain.drained_so_far(), 18);
+        drain.by_ref().for_each(drop);
+        assert_eq!(drain.drained_so_far(), 20);
+    }
+
+    #[test]
+    fn reserve() {
+        let buf = SmallBuffer::<u32, 4>::new();
+        buf.reserve(4);
+        assert!(buf.next.load(Ordering::Relaxed).is_null());
+        buf.push(0);
+        buf.reserve(8);
+        let second = buf.next.load(Ordering::Relaxed);
+        let third = unsafe { (*second).next.load(Ordering::Relaxed) };
+        assert!(!third.is_null());
+        assert!(unsafe { (*third).next.load(Ordering::Relaxed) }.is_null());
+        for i in 1..12 {
+            buf.push(i);
+        }
+        assert_eq!(buf.next.load(Ordering::Relaxed), second);
+        assert!(unsafe { (*third).next.load(Ordering::Relaxed) }.is_null());
+        assert_eq!(buf.iter().copied().collect::<Vec<_>>(), (0..12).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn drain_into() {
+        let mut buf = SmallBuffer::<String, 4>::new();
+        let mut vec = vec![String::from("before")];
+        buf.drain_into(&mut vec);
+        assert_eq!(vec, ["before"]);
+        for i in 0..10 {
+            buf.push(i.to_string());
+        }
+        let second = *buf.next.get_mut();
+        buf.drain_into(&mut vec);
+        assert!(buf.is_empty());
+        assert_eq!(vec.len(), 11);
+        assert_eq!(vec[1..], (0..10).map(|i| i.to_string()).collect::<Vec<_>>()[..]);
+        // the blocks are kept and reused
+        for i in 0..12 {
+            buf.push(i.to_string());
+        }
+        assert_eq!(*buf.next.get_mut(), second);
+        let mut vec = Vec::new();
+        buf.drain_into(&mut vec);
+        assert_eq!(vec, (0..12).map(|i| i.to_string()).collect::<Vec<_>>());
+        assert_eq!(buf.iter().count(), 0);
+    }
+
+    #[test]
+    fn get() {
+        let mut buf = SmallBuffer::<String>::new();
+        assert_eq!(buf.get(0), None);
+        for i in 0..20 {
+            buf.push(i.to_string());
+        }
+        for &i in &[0, 15, 16, 17, 19] {
+            assert_eq!(buf.get(i), Some(&i.to_string()));
+        }
+        assert_eq!(buf.get(20), None);
+        assert_eq!(buf.get(usize::MAX), None);
+
+        for &i in &[0, 15, 16, 17] {
+            buf.get_mut(i).unwrap().push('!');
+        }
+        assert!(buf.get_mut(20).is_none());
+        assert_eq!(buf.get(15).unwrap(), "15!");
+        assert_eq!(buf.get(16).unwrap(), "16!");
+        assert_eq!(buf.get(18).unwrap(), "18");
+    }
+
+    #[test]
+    fn clone() {
+        let mut buf = SmallBuffer::<String>::new();
+        for i in 0..40 {
+            buf.push(i.to_string());
+        }
+        let mut clone = buf.clone();
+        assert_eq!(clone.len(), 40);
+        buf.push(String::from("only in the original"));
+        assert_eq!(clone.drain().collect::<Vec<_>>(), (0..40).map(|i| i.to_string()).collect::<Vec<_>>());
+        assert_eq!(buf.drain().count(), 41);
+
+        let empty = SmallBuffer::<String>::new().clone();
+        assert!(empty.is_empty());
+        assert!(empty.next.load(Ordering::Relaxed).is_null());
+    }
+
+    #[test]
+    fn clear_and_release() {
+        let mut buf = SmallBuffer::<String, 4>::new();
+        buf.clear_and_release();
+        for i in 0..10 {
+            buf.push(i.to_string());
+        }
+        assert!(!buf.next.get_mut().is_null());
+        buf.clear_and_release();
+        assert!(buf.is_empty());
+        assert!(buf.next.get_mut().is_null());
+        // grows again after the release, and frees the new blocks on drop
+        for i in 0..10 {
+            buf.push(i.to_string());
+        }
+        assert_eq!(buf.drain().collect::<Vec<_>>(), (0..10).map(|i| i.to_string()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn vec_conversions() {
+        let strings: Vec<_> = (0..40).map(|i| i.to_string()).collect();
+        let buf = SmallBuffer::<String>::from(strings.clone());
+        assert_eq!(buf.len(), 40);
+        assert!(buf.iter().eq(&strings));
+        let vec: Vec<String> = buf.into();
+        assert_eq!(vec, strings);
+
+        let buf: SmallBuffer<String, 4> = Vec::new().into();
+        assert!(buf.is_empty());
+        assert!(Vec::from(buf).is_empty());
+    }
+
+    #[test]
+    fn append() {
+        let mut buf = SmallBuffer::<String>::new();
+        let mut other = SmallBuffer::<String>::new();
+        for i in 0..20 {
+            buf.push(i.to_string());
+        }
+        for i in 20..50 {
+            other.push(i.to_string());
+        }
+        buf.append(&mut other);
+        assert!(other.is_empty());
+        assert_eq!(buf.len(), 50);
+        assert_eq!(buf.drain().collect::<Vec<_>>(), (0..50).map(|i| i.to_string()).collect::<Vec<_>>());
+
+        other.push(String::from("reused"));
+        buf.append(&mut other);
+        buf.append(&mut SmallBuffer::new());
+        assert_eq!(buf.iter().collect::<Vec<_>>(), ["reused"]);
+    }
+
+    #[test]
+    fn with_capacity() {
+        let buf = SmallBuffer::<u32, 4>::with_capacity(4);
+        assert!(buf.next.load(Ordering::Relaxed).is_null());
+        let buf = SmallBuffer::<u32, 4>::with_capacity(9);
+        assert!(buf.is_empty());
+        let second = buf.next.load(Ordering::Relaxed);
+        let third = unsafe { (*second).next.load(Ordering::Relaxed) };
+        assert!(!third.is_null());
+        assert!(unsafe { (*third).next.load(Ordering::Relaxed) }.is_null());
+    }
+
+    #[test]
+    fn drain_with_blocks() {
+        let mut buf = SmallBuffer::<String>::new();
+        for i in 0..40 {
+            buf.push(i.to_string());
+        }
+        let drained: Vec<_> = buf.drain_with_blocks().collect();
+        assert_eq!(drained.iter().map(|x| &x.0).cloned().collect::<Vec<_>>(),
+                   (0..40).map(|i| i.to_string()).collect::<Vec<_>>());
+        let starts: Vec<_> = drained.iter().enumerate().filter(|x| (x.1).1).map(|x| x.0).collect();
+        assert_eq!(starts, [0, 16, 32]);
+        assert!(buf.is_empty());
+
+        for i in 0..20 {
+            buf.push(i.to_string());
+        }
+        drop(buf.drain_with_blocks().take(3));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn fused_iterators() {
+        fn assert_fused<I: FusedIterator>(_: &I) {}
+
+        let mut buf = SmallBuffer::<u32, 4>::new();
+        for i in 0..10 {
+            buf.push(i);
+        }
+        assert_fused(&buf.iter());
+        assert_fused(&buf.iter_mut());
+
+        let mut drain = buf.drain();
+        assert_fused(&drain);
+        for i in 0..10 {
+            assert_eq!(drain.len(), 10 - i);
+            assert_eq!(drain.next(), Some(i as u32));
+        }
+        assert_eq!(drain.len(), 0);
+        assert_eq!(drain.next(), None);
+        assert_eq!(drain.next(), None);
+        drop(drain);
+
+        for i in 0..10 {
+            buf.push(i);
+        }
+        let fused: Vec<_> = buf.drain().fuse().collect();
+        assert_eq!(fused, (0..10).collect::<Vec<_>>());
+        assert_fused(&buf.drain_with_blocks());
+
+        let mut into_iter = SmallBuffer::<u32, 4>::from(vec![1, 2]).into_iter();
+        assert_fused(&into_iter);
+        assert_eq!(into_iter.by_ref().count(), 2);
+        assert_eq!(into_iter.next(), None);
+    }
+
+    #[test]
+    fn size_hint() {
+        let mut buf = SmallBuffer::<u32>::new();
+        for i in 0..100 {
+            buf.push(i);
+        }
+        let mut iter = buf.iter();
+        assert_eq!(iter.size_hint(), (100, Some(100)));
+        iter.nth(20);
+        assert_eq!(iter.size_hint(), (79, Some(79)));
+        assert_eq!(buf.iter_mut().skip(50).size_hint(), (50, Some(50)));
+        assert_eq!(buf.iter().collect::<Vec<_>>().capacity(), 100);
+
+        let drained: Vec<_> = buf.drain().collect();
+        assert_eq!(drained.capacity(), 100);
+
+        let buf = SmallBuffer::<u32>::from(drained);
+        let mut into_iter = buf.into_iter();
+        into_iter.nth(30);
+        assert_eq!(into_iter.size_hint(), (69, Some(69)));
+        assert_eq!(into_iter.collect::<Vec<_>>().capacity(), 69);
+    }
+
+    #[test]
+    fn default() {
+        #[derive(Default)]
+        struct Particles {
+            ids: SmallBuffer<u32>,
+            #[allow(dead_code)]
+            names: SmallBuffer<String, 4>,
+        }
+
+        let particles = Particles::default();
+        assert!(particles.ids.is_empty());
+        particles.ids.push(7);
+        assert_eq!(particles.ids.iter().collect::<Vec<_>>(), [&7]);
+    }
+
+    #[test]
+    fn drain_into_ptr() {
+        let mut buf = SmallBuffer::<String>::new();
+        for i in 0..40 {
+            buf.push(i.to_string());
+        }
+        let mut storage: Vec<String> = Vec::with_capacity(25);
+        unsafe {
+            assert_eq!(buf.drain_into_ptr(storage.as_mut_ptr(), 25), 25);
+            storage.set_len(25);
+        }
+        assert_eq!(storage, (0..25).map(|i| i.to_string()).collect::<Vec<_>>());
+        assert_eq!(buf.len(), 15);
+        assert_eq!(buf.iter().cloned().collect::<Vec<_>>(), (25..40).map(|i| i.to_string()).collect::<Vec<_>>());
+
+        let mut rest: Vec<String> = Vec::with_capacity(100);
+        unsafe {
+            assert_eq!(buf.drain_into_ptr(rest.as_mut_ptr(), 0), 0);
+            assert_eq!(buf.drain_into_ptr(rest.as_mut_ptr(), 100), 15);
+            rest.set_len(15);
+        }
+        assert_eq!(rest, (25..40).map(|i| i.to_string()).collect::<Vec<_>>());
+        assert!(buf.is_empty());
+        buf.push("again".to_string());
+        assert_eq!(buf.iter().collect::<Vec<_>>(), ["again"]);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par_drain() {
+        use rayon::iter::ParallelIterator;
+        use std::sync::Arc;
+        use std::sync::atomic::AtomicUsize;
+
+        let mut buf = SmallBuffer::<u64>::new();
+        for i in 0..10_000 {
+            buf.push(i);
+        }
+        assert_eq!(buf.par_drain().sum::<u64>(), 10_000 * 9_999 / 2);
+        assert!(buf.is_empty());
+        buf.push(1);
+        assert_eq!(buf.iter().collect::<Vec<_>>(), [&1]);
+
+        struct Counted(usize, Arc<AtomicUsize>);
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                self.1.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let mut buf = SmallBuffer::<Counted>::new();
+        for i in 0..1000 {
+            buf.push(Counted(i, dropped.clone()));
+        }
+        let found = buf.par_drain().find_any(|x| x.0 == 500).unwrap();
+        assert_eq!(found.0, 500);
+        assert_eq!(dropped.load(Ordering::Relaxed), 999);
+        drop(found);
+        for i in 0..1000 {
+            buf.push(Counted(i, dropped.clone()));
+        }
+        drop(buf.par_drain());
+        assert_eq!(dropped.load(Ordering::Relaxed), 2000);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn index() {
+        let mut buf = SmallBuffer::<u32, 4>::new();
+        for i in 0..10 {
+            buf.push(i * 10);
+        }
+        assert_eq!(buf[0], 0);
+        assert_eq!(buf[3], 30);
+        assert_eq!(buf[4], 40);
+        assert_eq!(buf[9], 90);
+        buf[9] += 1;
+        buf[2] = 7;
+        assert_eq!(buf.iter().cloned().collect::<Vec<_>>(), [0, 10, 7, 30, 40, 50, 60, 70, 80, 91]);
+    }
+
+    #[test]
+    #[should_panic(expected = "index out of bounds: the len is 10 but the index is 10")]
+    fn index_out_of_bounds() {
+        let buf = SmallBuffer::<u32, 4>::new();
+        for i in 0..10 {
+            buf.push(i);
+        }
+        let _ = buf[10];
+    }
+
+    #[test]
+    #[should_panic(expected = "index out of bounds: the len is 0 but the index is 0")]
+    fn index_mut_out_of_bounds() {
+        let mut buf = SmallBuffer::<u32>::new();
+        buf[0] = 1;
+    }
+
+    #[test]
+    fn into_vec() {
+        let buf = SmallBuffer::<String>::new();
+        for i in 0..50 {
+            buf.push(i.to_string());
+        }
+        // unused blocks are freed too
+        buf.reserve(40);
+        let vec = buf.into_vec();
+        assert_eq!(vec.len(), 50);
+        assert_eq!(vec.capacity(), 50);
+        assert_eq!(vec, (0..50).map(|i| i.to_string()).collect::<Vec<_>>());
+
+        let buf = SmallBuffer::<String, 4>::from(vec);
+        assert_eq!(buf.len(), 50);
+        assert_eq!(buf.into_vec(), (0..50).map(|i| i.to_string()).collect::<Vec<_>>());
+        assert!(SmallBuffer::<String>::new().into_vec().is_empty());
+    }
+
+    #[test]
+    fn try_from_slice() {
+        let items = ["a".to_string(), "b".to_string(), "c".to_string(), "d".to_string()];
+        let buf = SmallBuffer::<String, 4>::try_from(&items[..]).unwrap();
+        assert_eq!(buf.iter().collect::<Vec<_>>(), items.iter().collect::<Vec<_>>());
+        assert!(SmallBuffer::<String, 4>::try_from(&items[..0]).unwrap().is_empty());
+
+        let err = SmallBuffer::<String, 3>::try_from(&items[..]).err().unwrap();
+        assert_eq!(err, CapacityError { len: 4, capacity: 3 });
+        assert_eq!(err.to_string(), "4 items don't fit in the capacity of 3");
+    }
+
+    #[test]
+    fn capacity() {
+        let mut buf = SmallBuffer::<usize>::new();
+        assert_eq!(buf.capacity(), 16);
+        for i in 0..40 {
+            buf.push(i);
+        }
+        assert_eq!(buf.capacity(), 48);
+        buf.clear();
+        assert_eq!(buf.capacity(), 48);
+        buf.clear_and_release();
+        assert_eq!(buf.capacity(), 16);
+        assert_eq!(SmallBuffer::<u8, 4>::with_capacity(9).capacity(), 12);
+    }
+
+    #[test]
+    fn swap_remove() {
+        let mut buf = SmallBuffer::<usize, 4>::new();
+        for i in 0..10 {
+            buf.push(i);
+        }
+        // the last item comes from the third block
+        assert_eq!(buf.swap_remove(0), 0);
+        assert_eq!(buf.swap_remove(5), 5);
+        assert_eq!(buf.swap_remove(7), 7);
+        assert_eq!(buf.len(), 7);
+        assert_eq!(buf.iter().cloned().collect::<Vec<_>>(), [9, 1, 2, 3, 4, 8, 6]);
+        // within the last block
+        assert_eq!(buf.swap_remove(4), 4);
+        assert_eq!(buf.swap_remove(5), 8);
+        assert_eq!(buf.iter().cloned().collect::<Vec<_>>(), [9, 1, 2, 3, 6]);
+        while !buf.is_empty() {
+            buf.swap_remove(0);
+        }
+        buf.push(10);
+        assert_eq!(buf.iter().cloned().collect::<Vec<_>>(), [10]);
+    }
+
+    #[test]
+    #[should_panic(expected = "swap_remove index (is 3) should be < len (is 3)")]
+    fn swap_remove_out_of_bounds() {
+        let mut buf = SmallBuffer::<usize, 4>::new();
+        for i in 0..3 {
+            buf.push(i);
+        }
+        buf.swap_remove(3);
+    }
+
+    #[test]
+    fn try_push() {
+        let mut buf = SmallBuffer::<usize, 4>::with_limit(6);
+        for i in 0..6 {
+            assert_eq!(buf.try_push(i), Ok(()));
+        }
+        assert_eq!(buf.try_push(6), Err(6));
+        buf.push(6);
+        assert_eq!(buf.len(), 7);
+        assert_eq!(buf.pop(), Some(6));
+        assert_eq!(buf.pop(), Some(5));
+        assert_eq!(buf.try_push(5), Ok(()));
+        assert!(buf.iter().cloned().eq(0..6));
+        assert_eq!(buf.clone().try_push(6), Err(6));
+
+        let unbounded = SmallBuffer::<usize, 4>::new();
+        for i in 0..100 {
+            assert_eq!(unbounded.try_push(i), Ok(()));
+        }
+    }
+
+    #[test]
+    fn try_push_threads() {
+        let buf = SmallBuffer::<usize, 4>::with_limit(150);
+        let rejected = AtomicUsize::new(0);
+        scope(|s| {
+            for t in 0..4 {
+                let buf = &buf;
+                let rejected = &rejected;
+                s.spawn(move || {
+                    for i in 0..100 {
+                        if let Err(item) = buf.try_push(t * 100 + i) {
+                            assert_eq!(item, t * 100 + i);
+                            rejected.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(buf.len(), 150);
+        assert_eq!(rejected.into_inner(), 250);
+        let mut items: Vec<_> = buf.iter().cloned().collect();
+        items.sort();
+        items.dedup();
+        assert_eq!(items.len(), 150);
+    }
+
+    #[test]
+    fn len() {
+        let mut buf = SmallBuffer::<usize>::new();
+        assert!(buf.is_empty());
+        for i in 0..40 {
+            buf.push(i);
+            assert_eq!(buf.len(), i + 1);
+        }
+        assert!(!buf.is_empty());
+        assert_eq!(buf.drain().count(), 40);
+        assert_eq!(buf.len(), 0);
+        assert!(buf.is_empty());
     }
 }
\ No newline at end of file
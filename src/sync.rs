@@ -0,0 +1,423 @@
+//! The synchronization primitives used by `Pool`.
+//!
+//! With the `std` feature the locks wrap the locks of `std`. Without it they are simple spinning
+//! replacements that only need `core` and `alloc`.
+//!
+//! The free lists implement `FreeList`. With the `std` and `crossbeam` features they are the
+//! lock-free queue and stack of crossbeam. Otherwise they are a queue behind a lock and a Treiber
+//! stack with lock-free pushes, which need no other crate.
+//!
+//! The locks ignore poisoning: the data they guard in this crate only records memory that exists,
+//! and a panic while it is locked doesn't leave it invalid.
+
+#[cfg(feature = "std")]
+pub use self::with_std::*;
+#[cfg(not(feature = "std"))]
+pub use self::spin::*;
+#[cfg(all(feature = "std", feature = "crossbeam"))]
+pub use self::with_crossbeam::*;
+#[cfg(not(all(feature = "std", feature = "crossbeam")))]
+pub use self::builtin::*;
+
+/// A list of free slots shared between threads.
+pub trait FreeList<T> {
+    /// Adds an item to the list.
+    fn push(&self, t: T);
+
+    /// Takes an item from the list, or returns `None` if it is empty.
+    fn try_pop(&self) -> Option<T>;
+}
+
+/// The shared list of free slots of a `Pool`, a queue or a stack.
+// the queue is large because it is padded to cache lines, boxing it would only add an indirection
+#[allow(clippy::large_enum_variant)]
+pub enum FreeSlots<T> {
+    Fifo(Queue<T>),
+    Lifo(Stack<T>),
+}
+
+impl<T> FreeSlots<T> {
+    #[inline]
+    pub fn new(lifo: bool) -> Self {
+        if lifo {
+            FreeSlots::Lifo(Stack::new())
+        } else {
+            FreeSlots::Fifo(Queue::new())
+        }
+    }
+}
+
+impl<T> FreeList<T> for FreeSlots<T> {
+    #[inline]
+    fn push(&self, t: T) {
+        match *self {
+            FreeSlots::Fifo(ref x) => x.push(t),
+            FreeSlots::Lifo(ref x) => x.push(t),
+        }
+    }
+
+    #[inline]
+    fn try_pop(&self) -> Option<T> {
+        match *self {
+            FreeSlots::Fifo(ref x) => x.try_pop(),
+            FreeSlots::Lifo(ref x) => x.try_pop(),
+        }
+    }
+}
+
+#[cfg(all(feature = "std", feature = "crossbeam"))]
+mod with_crossbeam {
+    use crossbeam::sync::{MsQueue, TreiberStack};
+
+    use super::FreeList;
+
+    pub use crossbeam::sync::MsQueue as Queue;
+    pub use crossbeam::sync::TreiberStack as Stack;
+
+    impl<T> FreeList<T> for MsQueue<T> {
+        #[inline]
+        fn push(&self, t: T) {
+            MsQueue::push(self, t);
+        }
+
+        #[inline]
+        fn try_pop(&self) -> Option<T> {
+            MsQueue::try_pop(self)
+        }
+    }
+
+    impl<T> FreeList<T> for TreiberStack<T> {
+        #[inline]
+        fn push(&self, t: T) {
+            TreiberStack::push(self, t);
+        }
+
+        #[inline]
+        fn try_pop(&self) -> Option<T> {
+            TreiberStack::try_pop(self)
+        }
+    }
+}
+
+#[cfg(not(all(feature = "std", feature = "crossbeam")))]
+mod builtin {
+    use alloc::boxed::Box;
+    use alloc::collections::VecDeque;
+    use core::hint;
+    use core::marker::PhantomData;
+    use core::ptr;
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use super::{FreeList, Mutex};
+
+    pub use self::TreiberStack as Stack;
+
+    /// A FIFO queue behind a lock.
+    pub struct Queue<T>(Mutex<VecDeque<T>>);
+
+    impl<T> Queue<T> {
+        #[inline]
+        pub fn new() -> Self {
+            Queue(Mutex::new(VecDeque::new()))
+        }
+    }
+
+    impl<T> FreeList<T> for Queue<T> {
+        #[inline]
+        fn push(&self, t: T) {
+            self.0.lock().push_back(t);
+        }
+
+        #[inline]
+        fn try_pop(&self) -> Option<T> {
+            self.0.lock().pop_front()
+        }
+    }
+
+    /// A LIFO stack with lock-free pushes.
+    ///
+    /// Pops exclude each other with a flag. A node in the stack can then only be removed by the one
+    /// thread popping, so it can't be popped and pushed again between reading it and replacing it,
+    /// which makes the stack safe from the ABA problem and lets the popping thread free the node right
+    /// away, without a memory reclamation scheme.
+    ///
+    /// A push can still replace a head that was popped and freed meanwhile, if a new node got the
+    /// same address: the comparison succeeds and the new node is rightly linked to the node now at
+    /// that address. The links are kept as addresses with exposed provenance for that reason, so
+    /// they always refer to the node at the address and not to the freed one.
+    pub struct TreiberStack<T> {
+        // the address of the first node, 0 if the stack is empty
+        head: AtomicUsize,
+        popping: AtomicBool,
+        _marker: PhantomData<Box<Node<T>>>,
+    }
+
+    struct Node<T> {
+        value: T,
+        next: usize,
+    }
+
+    unsafe impl<T: Send> Send for TreiberStack<T> {}
+
+    unsafe impl<T: Send> Sync for TreiberStack<T> {}
+
+    impl<T> TreiberStack<T> {
+        #[inline]
+        pub fn new() -> Self {
+            TreiberStack {
+                head: AtomicUsize::new(0),
+                popping: AtomicBool::new(false),
+                _marker: PhantomData,
+            }
+        }
+    }
+
+    impl<T> FreeList<T> for TreiberStack<T> {
+        #[inline]
+        fn push(&self, t: T) {
+            let node = Box::into_raw(Box::new(Node {
+                value: t,
+                next: 0,
+            }));
+            let address = node.expose_provenance();
+            let mut head = self.head.load(Ordering::Relaxed);
+            loop {
+                unsafe { (*node).next = head };
+                match self.head.compare_exchange_weak(head, address, Ordering::Release, Ordering::Relaxed) {
+                    Ok(_) => return,
+                    Err(x) => head = x,
+                }
+            }
+        }
+
+        #[inline]
+        fn try_pop(&self) -> Option<T> {
+            if self.head.load(Ordering::Relaxed) == 0 {
+                return None;
+            }
+            while self.popping
+                .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_err() {
+                hint::spin_loop();
+            }
+            let mut head = self.head.load(Ordering::Acquire);
+            while head != 0 {
+                // only pushes can happen meanwhile, so `head` stays in the stack until replaced here
+                let next = unsafe { (*node_at::<T>(head)).next };
+                match self.head.compare_exchange_weak(head, next, Ordering::Acquire, Ordering::Acquire) {
+                    Ok(_) => break,
+                    Err(x) => head = x,
+                }
+            }
+            self.popping.store(false, Ordering::Release);
+            if head == 0 {
+                None
+            } else {
+                Some(unsafe { Box::from_raw(node_at::<T>(head)) }.value)
+            }
+        }
+    }
+
+    impl<T> Drop for TreiberStack<T> {
+        fn drop(&mut self) {
+            let mut node = *self.head.get_mut();
+            while node != 0 {
+                let boxed = unsafe { Box::from_raw(node_at::<T>(node)) };
+                node = boxed.next;
+            }
+        }
+    }
+
+    /// The node at `address`, which was exposed by `TreiberStack::push`.
+    #[inline]
+    fn node_at<T>(address: usize) -> *mut Node<T> {
+        ptr::with_exposed_provenance_mut(address)
+    }
+}
+
+#[cfg(feature = "std")]
+mod with_std {
+    use std::sync::{self, PoisonError};
+
+    pub use std::sync::{MutexGuard, RwLockReadGuard, RwLockWriteGuard};
+
+    pub struct Mutex<T>(sync::Mutex<T>);
+
+    impl<T> Mutex<T> {
+        #[inline]
+        pub fn new(t: T) -> Self {
+            Mutex(sync::Mutex::new(t))
+        }
+
+        #[inline]
+        pub fn lock(&self) -> MutexGuard<'_, T> {
+            self.0.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        #[cfg(test)]
+        pub fn is_poisoned(&self) -> bool {
+            self.0.is_poisoned()
+        }
+    }
+
+    pub struct RwLock<T>(sync::RwLock<T>);
+
+    impl<T> RwLock<T> {
+        #[inline]
+        pub fn new(t: T) -> Self {
+            RwLock(sync::RwLock::new(t))
+        }
+
+        #[inline]
+        pub fn read(&self) -> RwLockReadGuard<'_, T> {
+            self.0.read().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        #[inline]
+        pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+            self.0.write().unwrap_or_else(PoisonError::into_inner)
+        }
+    }
+}
+
+#[cfg(not(feature = "std"))]
+mod spin {
+    use core::cell::UnsafeCell;
+    use core::hint;
+    use core::ops::{Deref, DerefMut};
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    pub struct Mutex<T> {
+        locked: AtomicBool,
+        data: UnsafeCell<T>,
+    }
+
+    unsafe impl<T: Send> Send for Mutex<T> {}
+
+    unsafe impl<T: Send> Sync for Mutex<T> {}
+
+    impl<T> Mutex<T> {
+        #[inline]
+        pub fn new(t: T) -> Self {
+            Mutex {
+                locked: AtomicBool::new(false),
+                data: UnsafeCell::new(t),
+            }
+        }
+
+        #[inline]
+        pub fn lock(&self) -> MutexGuard<'_, T> {
+            while self.locked
+                .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_err() {
+                hint::spin_loop();
+            }
+            MutexGuard {
+                mutex: self,
+            }
+        }
+    }
+
+    pub struct MutexGuard<'a, T: 'a> {
+        mutex: &'a Mutex<T>,
+    }
+
+    impl<'a, T> Deref for MutexGuard<'a, T> {
+        type Target = T;
+
+        #[inline]
+        fn deref(&self) -> &T {
+            unsafe { &*self.mutex.data.get() }
+        }
+    }
+
+    impl<'a, T> DerefMut for MutexGuard<'a, T> {
+        #[inline]
+        fn deref_mut(&mut self) -> &mut T {
+            unsafe { &mut *self.mutex.data.get() }
+        }
+    }
+
+    impl<'a, T> Drop for MutexGuard<'a, T> {
+        #[inline]
+        fn drop(&mut self) {
+            self.mutex.locked.store(false, Ordering::Release);
+        }
+    }
+
+    /// Readers exclude each other too. The lock is only held briefly, so it isn't worth the
+    /// complexity of a real readers-writer lock.
+    pub struct RwLock<T>(Mutex<T>);
+
+    pub type RwLockReadGuard<'a, T> = MutexGuard<'a, T>;
+    pub type RwLockWriteGuard<'a, T> = MutexGuard<'a, T>;
+
+    impl<T> RwLock<T> {
+        #[inline]
+        pub fn new(t: T) -> Self {
+            RwLock(Mutex::new(t))
+        }
+
+        #[inline]
+        pub fn read(&self) -> RwLockReadGuard<'_, T> {
+            self.0.lock()
+        }
+
+        #[inline]
+        pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+            self.0.lock()
+        }
+    }
+}
+
+#[cfg(all(test, not(all(feature = "std", feature = "crossbeam"))))]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn treiber_stack() {
+        let stack = TreiberStack::new();
+        assert_eq!(stack.try_pop(), None::<Arc<u32>>);
+        for i in 0..10 {
+            stack.push(Arc::new(i));
+        }
+        for i in (5..10).rev() {
+            assert_eq!(*stack.try_pop().unwrap(), i);
+        }
+        let last = Arc::new(10);
+        stack.push(last.clone());
+        assert_eq!(Arc::strong_count(&last), 2);
+        // the items left are dropped with the stack
+        drop(stack);
+        assert_eq!(Arc::strong_count(&last), 1);
+    }
+
+    #[test]
+    fn treiber_stack_threads() {
+        use crossbeam::scope;
+
+        let stack = TreiberStack::new();
+        let popped = AtomicUsize::new(0);
+        scope(|s| {
+            for t in 0..4 {
+                let stack = &stack;
+                let popped = &popped;
+                s.spawn(move || {
+                    for i in 0..1000 {
+                        stack.push(t * 1000 + i);
+                        if i % 2 == 0 {
+                            popped.fetch_add(stack.try_pop().unwrap(), Ordering::Relaxed);
+                        }
+                    }
+                });
+            }
+        });
+        while let Some(x) = stack.try_pop() {
+            popped.fetch_add(x, Ordering::Relaxed);
+        }
+        assert_eq!(popped.load(Ordering::Relaxed), (0..4000).sum::<usize>());
+    }
+}
@@ -0,0 +1,10 @@
+extern crate veryfast;
+
+use std::rc::Rc;
+use veryfast::small_buffer::SmallBuffer;
+
+fn assert_send<T: Send>() {}
+
+fn main() {
+    assert_send::<SmallBuffer<Rc<i32>>>();
+}
@@ -0,0 +1,10 @@
+extern crate veryfast;
+
+use std::cell::Cell;
+use veryfast::small_buffer::SmallBuffer;
+
+fn assert_sync<T: Sync>() {}
+
+fn main() {
+    assert_sync::<SmallBuffer<Cell<i32>>>();
+}
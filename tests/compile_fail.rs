@@ -0,0 +1,10 @@
+// The cases only cover `SmallBuffer`, which needs `std`.
+#![cfg(feature = "std")]
+
+extern crate trybuild;
+
+#[test]
+fn compile_fail() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/compile-fail/*.rs");
+}
@@ -0,0 +1,22 @@
+// Only checks that the NUMA hint doesn't get in the way. This machine might have a single node.
+#![cfg(feature = "numa")]
+
+extern crate veryfast;
+
+use veryfast::pool::{Pool, PoolBuilder};
+
+#[test]
+fn allocates_on_node_zero() {
+    let pool = Pool::<[u64; 4]>::with_numa_node(0);
+    let objs: Vec<_> = (0..pool.batch_size() as u64 * 3).map(|i| pool.push([i; 4])).collect();
+    for (i, obj) in objs.iter().enumerate() {
+        assert_eq!(obj[3], i as u64);
+    }
+    assert_eq!(pool.capacity(), pool.batch_size() * 3);
+}
+
+#[test]
+fn invalid_node_is_ignored() {
+    let pool = PoolBuilder::<u8>::new().numa_node(1 << 20).build();
+    assert_eq!(*pool.push(7), 7);
+}
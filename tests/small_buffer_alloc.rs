@@ -0,0 +1,132 @@
+// Uses its own test binary, since counting allocations needs a global allocator.
+#![cfg(feature = "std")]
+
+extern crate crossbeam;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+extern crate veryfast;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::Barrier;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use veryfast::small_buffer::SmallBuffer;
+
+struct Counting;
+
+// counted per thread, so tests running in parallel don't see each other's allocations
+thread_local! {
+    static COUNTING: Cell<bool> = const { Cell::new(false) };
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    static DEALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if COUNTING.with(Cell::get) {
+            ALLOCATIONS.with(|x| x.set(x.get() + 1));
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if COUNTING.with(Cell::get) {
+            DEALLOCATIONS.with(|x| x.set(x.get() + 1));
+        }
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Runs `f`, returning the number of allocations and deallocations it made on the current thread.
+fn counted<F: FnOnce()>(f: F) -> (usize, usize) {
+    ALLOCATIONS.with(|x| x.set(0));
+    DEALLOCATIONS.with(|x| x.set(0));
+    COUNTING.with(|x| x.set(true));
+    f();
+    COUNTING.with(|x| x.set(false));
+    (ALLOCATIONS.with(Cell::get), DEALLOCATIONS.with(Cell::get))
+}
+
+/// Pushes 100 items from 4 threads, returning the number of allocations made during the pushes.
+fn concurrent_pushes(buf: &SmallBuffer<u64>) -> usize {
+    let threads = 4;
+    let start = Barrier::new(threads);
+    let allocations = AtomicUsize::new(0);
+    crossbeam::scope(|s| {
+        for t in 0..threads {
+            let (start, allocations) = (&start, &allocations);
+            s.spawn(move || {
+                start.wait();
+                let (allocs, _) = counted(|| {
+                    for i in 0..25 {
+                        buf.push(t as u64 * 25 + i);
+                    }
+                });
+                allocations.fetch_add(allocs, Ordering::SeqCst);
+            });
+        }
+    });
+    allocations.into_inner()
+}
+
+#[test]
+fn reserve_prevents_allocations() {
+    let mut buf = SmallBuffer::<u64>::new();
+    assert!(concurrent_pushes(&buf) > 0);
+    assert_eq!(buf.drain().count(), 100);
+
+    let mut buf = SmallBuffer::<u64>::new();
+    buf.reserve(100);
+    assert_eq!(concurrent_pushes(&buf), 0);
+    let mut values: Vec<_> = buf.drain().collect();
+    values.sort();
+    assert_eq!(values, (0..100).collect::<Vec<_>>());
+}
+
+#[test]
+fn clear_and_release_deallocates() {
+    let mut buf = SmallBuffer::<u64, 4>::new();
+    for i in 0..10 {
+        buf.push(i);
+    }
+    assert_eq!(counted(|| buf.clear()), (0, 0));
+    for i in 0..10 {
+        buf.push(i);
+    }
+    // the second and third blocks
+    assert_eq!(counted(|| buf.clear_and_release()), (0, 2));
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn with_capacity_prevents_allocations() {
+    let buf = SmallBuffer::<u64>::with_capacity(100);
+    let (allocs, _) = counted(|| {
+        for i in 0..100 {
+            buf.push(i);
+        }
+    });
+    assert_eq!(allocs, 0);
+    assert_eq!(buf.len(), 100);
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn par_drain_keeps_blocks() {
+    use rayon::iter::ParallelIterator;
+
+    let mut buf = SmallBuffer::<u64>::new();
+    for i in 0..1000 {
+        buf.push(i);
+    }
+    assert_eq!(buf.par_drain().count(), 1000);
+    let (allocs, _) = counted(|| {
+        for i in 0..1000 {
+            buf.push(i);
+        }
+    });
+    assert_eq!(allocs, 0);
+}